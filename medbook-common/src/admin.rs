@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use anyhow::{Context, bail};
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+
+/// Bearer token operators send to the `/admin` routes, from `ADMIN_API_TOKEN`.
+#[derive(Clone)]
+pub struct AdminToken(Arc<str>);
+
+impl AdminToken {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let token = std::env::var("ADMIN_API_TOKEN").context("ADMIN_API_TOKEN is invalid")?;
+        if token.len() < 16 {
+            bail!("ADMIN_API_TOKEN must be at least 16 characters long");
+        }
+        Ok(Self(token.into()))
+    }
+
+    fn matches(&self, candidate: &str) -> bool {
+        let (expected, candidate) = (self.0.as_bytes(), candidate.as_bytes());
+
+        // Compared in constant time, only the length may leak
+        expected.len() == candidate.len()
+            && expected
+                .iter()
+                .zip(candidate)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// Lets through the requests that carry `Authorization: Bearer <ADMIN_API_TOKEN>`.
+/// Meant for `axum::middleware::from_fn_with_state`.
+pub async fn admin_authorization(
+    State(token): State<AdminToken>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|candidate| token.matches(candidate));
+
+    if authorized {
+        Ok(next.run(req).await)
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
@@ -0,0 +1,57 @@
+use std::error::Error;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use diesel::result::Error as DieselError;
+use thiserror::Error;
+use tracing::error;
+
+/// Errors every service can return from its routes.
+///
+/// Services wrap it in their own `AppError` next to their domain-specific variants.
+#[derive(Error, Debug)]
+pub enum BaseError {
+    #[error("Service \"{0}\" is unreachable")]
+    ServiceUnreachable(String),
+
+    #[error("Resource \"{0}\" is not authorized for the current user")]
+    ForbiddenResource(String),
+
+    #[error("Resource \"{0}\" was not found")]
+    NotFound(String),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl IntoResponse for BaseError {
+    fn into_response(self) -> Response {
+        let (status, message) = match &self {
+            BaseError::ServiceUnreachable(_) => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
+            BaseError::ForbiddenResource(_) => (StatusCode::FORBIDDEN, self.to_string()),
+            BaseError::NotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
+            BaseError::Other(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error".into(),
+            ),
+        };
+
+        error_response(&self, status, message)
+    }
+}
+
+impl From<DieselError> for BaseError {
+    fn from(err: DieselError) -> Self {
+        BaseError::Other(anyhow::Error::new(err))
+    }
+}
+
+/// Logs `err` and turns it into a plain-text response.
+pub fn error_response(err: &dyn Error, status: StatusCode, message: String) -> Response {
+    error!("Error: {}", err);
+    error!("Detailed error: {:#?}", err.source());
+
+    (status, message).into_response()
+}
@@ -0,0 +1,8 @@
+use crate::{db::DbPool, rmq::Rmq, shutdown::Shutdown};
+
+/// The part of a service's `AppState` that the shared outbox relay and consumers rely on.
+pub trait BaseState: Clone + Send + Sync + 'static {
+    fn db_pool(&self) -> &DbPool;
+    fn rmq_client(&self) -> &Rmq;
+    fn shutdown(&self) -> &Shutdown;
+}
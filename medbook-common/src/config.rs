@@ -0,0 +1,15 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+/// Reads `key` from the environment, falling back to `default` when it is unset.
+pub fn env_or<T>(key: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(key) {
+        Ok(value) => value.parse().with_context(|| format!("{} is invalid", key)),
+        Err(_) => Ok(default),
+    }
+}
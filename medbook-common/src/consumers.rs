@@ -0,0 +1,546 @@
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures_lite::StreamExt;
+use lapin::{
+    BasicProperties, Channel,
+    message::Delivery,
+    options::{
+        BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions,
+        BasicQosOptions, ConfirmSelectOptions,
+    },
+    types::{AMQPValue, FieldTable},
+};
+use medbook_events::{Event, EventEnvelope};
+use serde::Serialize;
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::{sync::mpsc, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, info_span, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::{app_state::BaseState, config::env_or, rmq, telemetry};
+
+pub type ConsumerFn<E, S> = fn(EventEnvelope<E>, S) -> BoxFuture<'static, Result<()>>;
+
+/// Header counting how many times a message has gone through the retry queue.
+pub const RETRY_COUNT_HEADER: &str = "x-retry-count";
+/// Header holding the last handler error of a dead-lettered message.
+pub const ERROR_HEADER: &str = "x-error";
+/// Header holding when a message was dead-lettered, in RFC 3339.
+pub const FAILED_AT_HEADER: &str = "x-failed-at";
+
+/// Marks a handler error as permanent: the message is dead-lettered right away
+/// instead of being retried. Any other error is considered retryable.
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub struct Poison(#[from] pub anyhow::Error);
+
+/// What a consumer task is currently doing.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConsumerState {
+    Starting,
+    Running,
+    /// The consumer failed and waits before reconnecting.
+    Restarting {
+        error: String,
+    },
+    Stopped,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ConsumerStatus {
+    pub queue: &'static str,
+    #[serde(flatten)]
+    pub state: ConsumerState,
+    pub since: DateTime<Utc>,
+}
+
+/// Consumers started by this service, used by the health checks and the dead
+/// letter admin routes.
+static CONSUMERS: Mutex<Vec<ConsumerStatus>> = Mutex::new(Vec::new());
+
+pub fn statuses() -> Vec<ConsumerStatus> {
+    CONSUMERS.lock().unwrap().clone()
+}
+
+pub fn queues() -> Vec<&'static str> {
+    statuses().iter().map(|status| status.queue).collect()
+}
+
+fn set_state(queue: &'static str, state: ConsumerState) {
+    let status = ConsumerStatus {
+        queue,
+        state,
+        since: Utc::now(),
+    };
+    let mut consumers = CONSUMERS.lock().unwrap();
+
+    match consumers
+        .iter_mut()
+        .find(|consumer| consumer.queue == queue)
+    {
+        Some(consumer) => *consumer = status,
+        None => consumers.push(status),
+    }
+}
+
+/// Retry and concurrency settings, read from `CONSUMER_*` environment variables.
+///
+/// `prefetch` and `concurrency` can be overridden per queue with
+/// `CONSUMER_<QUEUE>_PREFETCH` and `CONSUMER_<QUEUE>_CONCURRENCY`, where `<QUEUE>`
+/// is the queue name in upper case with dots replaced by underscores, e.g.
+/// `CONSUMER_INVENTORY_ORDER_REQUESTED_CONCURRENCY`.
+#[derive(Debug, Clone)]
+pub struct ConsumerConfig {
+    /// Retries of a failing message before it is dead-lettered.
+    pub max_retries: i64,
+    /// How long a failed message waits in the retry queue.
+    pub retry_delay: Duration,
+    /// Unacked messages the broker hands to this consumer at once.
+    pub prefetch: u16,
+    /// Handlers running at the same time, each one working through its own lane.
+    pub concurrency: usize,
+}
+
+impl ConsumerConfig {
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            max_retries: env_or("CONSUMER_MAX_RETRIES", 5)?,
+            retry_delay: Duration::from_secs(env_or("CONSUMER_RETRY_DELAY_SECS", 10)?),
+            prefetch: env_or("CONSUMER_PREFETCH", 20)?,
+            concurrency: env_or("CONSUMER_CONCURRENCY", 4)?,
+        })
+    }
+
+    fn for_queue(mut self, queue: &str) -> Result<Self> {
+        let prefix = format!("CONSUMER_{}", queue.to_uppercase().replace('.', "_"));
+        self.prefetch = env_or(&format!("{}_PREFETCH", prefix), self.prefetch)?;
+        self.concurrency = env_or(&format!("{}_CONCURRENCY", prefix), self.concurrency)?.max(1);
+        Ok(self)
+    }
+}
+
+type Lane<E> = mpsc::UnboundedSender<(Delivery, EventEnvelope<E>)>;
+
+/// Consumes `E` from its own queue and acks each delivery once it is handled.
+///
+/// Deliveries are spread over `concurrency` lanes by [`Event::ordering_key`], so
+/// events of one aggregate are still handled one at a time and in order.
+///
+/// A retryable error sends the message to `<queue>.retry`, from which it comes
+/// back after `retry_delay`. A [`Poison`] error, a message that cannot be
+/// parsed or has another [`Event::VERSION`], or one that ran out of retries is
+/// parked in `<queue>.dlq`.
+///
+/// On shutdown the consumer is cancelled, handlers already running finish, and
+/// deliveries that were still waiting in a lane are nacked back to the queue.
+pub fn consume<E: Event, S: BaseState>(
+    consumer_fn: ConsumerFn<E, S>,
+    state: S,
+    config: ConsumerConfig,
+) -> Result<()> {
+    let queue_name = E::TYPE;
+    let config = config.for_queue(queue_name)?;
+    set_state(queue_name, ConsumerState::Starting);
+    let shutdown = state.shutdown().clone();
+
+    shutdown.clone().spawn(async move {
+        while !shutdown.is_cancelled() {
+            let state = state.clone();
+            let config = &config;
+
+            let future = Box::pin(async move {
+                let channel = state.rmq_client().create_channel().await?;
+                channel
+                    .confirm_select(ConfirmSelectOptions::default())
+                    .await?;
+                channel
+                    .basic_qos(config.prefetch, BasicQosOptions::default())
+                    .await?;
+                rmq::declare_queue(&channel, queue_name).await?;
+                rmq::declare_retry_queue(&channel, queue_name).await?;
+                rmq::declare_dead_letter_queue(&channel, queue_name).await?;
+
+                let mut consumer = channel
+                    .basic_consume(
+                        queue_name,
+                        queue_name,
+                        BasicConsumeOptions::default(),
+                        FieldTable::default(),
+                    )
+                    .await?;
+
+                set_state(queue_name, ConsumerState::Running);
+                info!(
+                    queue = queue_name,
+                    lanes = config.concurrency,
+                    prefetch = config.prefetch,
+                    "Consumer created"
+                );
+
+                // Lanes are unbounded, but never hold more than `prefetch` deliveries
+                // between them since the broker stops sending until some are acked.
+                // `stop` is cancelled on shutdown, or once one lane failed.
+                let stop = state.shutdown().child_token();
+                let mut workers = JoinSet::new();
+                let lanes: Vec<Lane<E>> = (0..config.concurrency)
+                    .map(|_| {
+                        let (lane, deliveries) = mpsc::unbounded_channel();
+                        workers.spawn(work(
+                            deliveries,
+                            consumer_fn,
+                            state.clone(),
+                            channel.clone(),
+                            config.clone(),
+                            stop.clone(),
+                        ));
+                        lane
+                    })
+                    .collect();
+                let mut next_lane = 0;
+
+                let mut result = async {
+                    loop {
+                        tokio::select! {
+                            delivery = consumer.next() => {
+                                let Some(delivery) = delivery else { break };
+                                let delivery = delivery?;
+
+                                match parse::<E>(&delivery.data) {
+                                    Ok(event) => {
+                                        let lane = match event.payload.ordering_key() {
+                                            Some(key) => lane_for(&key, lanes.len()),
+                                            None => {
+                                                next_lane = (next_lane + 1) % lanes.len();
+                                                next_lane
+                                            }
+                                        };
+
+                                        // A closed lane means its worker failed, which
+                                        // `join_next` reports below.
+                                        let _ = lanes[lane].send((delivery, event));
+                                    }
+                                    Err(e) => {
+                                        let e = e.into();
+                                        handle_failure(&channel, queue_name, config, &delivery, e)
+                                            .await?;
+                                        delivery.ack(BasicAckOptions::default()).await?;
+                                    }
+                                }
+                            }
+                            Some(result) = workers.join_next() => result??,
+                            _ = state.shutdown().cancelled() => {
+                                channel
+                                    .basic_cancel(queue_name, BasicCancelOptions::default())
+                                    .await?;
+                                break;
+                            }
+                        }
+                    }
+                    Ok::<_, anyhow::Error>(())
+                }
+                .await;
+
+                // Let the lanes finish what they were handed before reconnecting. Once
+                // one failed, the others finish the handler they are running and nack
+                // the rest of their deliveries.
+                if result.is_err() {
+                    stop.cancel();
+                }
+                drop(lanes);
+                while let Some(joined) = workers.join_next().await {
+                    if let Err(e) = joined.map_err(anyhow::Error::from).and_then(|lane| lane)
+                        && result.is_ok()
+                    {
+                        stop.cancel();
+                        result = Err(e);
+                    }
+                }
+                result?;
+
+                if state.shutdown().is_cancelled() {
+                    channel.close(200, "Consumer shutting down").await?;
+                    info!(queue = queue_name, "Consumer stopped");
+                }
+
+                Ok::<_, anyhow::Error>(())
+            });
+
+            match future.await {
+                Ok(_) => {}
+                Err(e) => {
+                    set_state(
+                        queue_name,
+                        ConsumerState::Restarting {
+                            error: format!("{:#}", e),
+                        },
+                    );
+                    error!(
+                        queue = queue_name,
+                        error = format!("{:#}", e),
+                        retry_in_secs = 5,
+                        "Consumer failed, restarting"
+                    );
+                    shutdown.sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+
+        set_state(queue_name, ConsumerState::Stopped);
+    });
+
+    Ok(())
+}
+
+fn lane_for(key: &str, lanes: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % lanes as u64) as usize
+}
+
+/// Handles the deliveries of one lane in order. Returns an error only when a
+/// delivery could not be acked or moved, which restarts the whole consumer.
+async fn work<E: Event, S: BaseState>(
+    mut deliveries: mpsc::UnboundedReceiver<(Delivery, EventEnvelope<E>)>,
+    consumer_fn: ConsumerFn<E, S>,
+    state: S,
+    channel: Channel,
+    config: ConsumerConfig,
+    stop: CancellationToken,
+) -> Result<()> {
+    while let Some((delivery, event)) = deliveries.recv().await {
+        if stop.is_cancelled() {
+            delivery
+                .nack(BasicNackOptions {
+                    requeue: true,
+                    ..Default::default()
+                })
+                .await?;
+            continue;
+        }
+
+        // Continues the trace of the request that led to the event.
+        let span = info_span!(
+            "consume",
+            otel.name = format!("{} process", E::TYPE),
+            otel.kind = "consumer",
+            event_type = E::TYPE,
+            event_id = %event.event_id,
+            correlation_id = %event.correlation_id,
+            producer = %event.producer,
+        );
+        span.set_parent(telemetry::extract_headers(delivery.properties.headers()));
+        span.in_scope(|| info!("Received event"));
+
+        let start = Instant::now();
+        let result = consumer_fn(event, state.clone()).instrument(span).await;
+        metrics::histogram!("consumer_handler_duration_seconds", "queue" => E::TYPE)
+            .record(start.elapsed());
+
+        match result {
+            Ok(_) => {
+                metrics::counter!("consumer_messages_total", "queue" => E::TYPE, "outcome" => "ok")
+                    .increment(1)
+            }
+            Err(e) => handle_failure(&channel, E::TYPE, &config, &delivery, e).await?,
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+/// Moves a failed delivery to the retry or dead letter queue. The caller acks the
+/// original only once this succeeds, so the message is never lost in between.
+async fn handle_failure(
+    channel: &Channel,
+    queue_name: &'static str,
+    config: &ConsumerConfig,
+    delivery: &Delivery,
+    err: anyhow::Error,
+) -> Result<()> {
+    let headers = delivery.properties.headers();
+    let retries = rmq::int_header(headers, RETRY_COUNT_HEADER).unwrap_or(0);
+    let poison = err.is::<Poison>();
+
+    if !poison && retries < config.max_retries {
+        metrics::counter!("consumer_messages_total", "queue" => queue_name, "outcome" => "retried")
+            .increment(1);
+        warn!(
+            queue = queue_name,
+            retry = retries + 1,
+            max_retries = config.max_retries,
+            retry_in_secs = config.retry_delay.as_secs(),
+            error = format!("{:#}", err),
+            "Handling a message failed, retrying"
+        );
+
+        let mut headers = headers.clone().unwrap_or_default();
+        headers.insert(
+            RETRY_COUNT_HEADER.into(),
+            AMQPValue::LongLongInt(retries + 1),
+        );
+
+        rmq::publish_confirmed(
+            channel,
+            &rmq::retry_queue(queue_name),
+            &delivery.data,
+            BasicProperties::default()
+                .with_headers(headers)
+                .with_expiration(config.retry_delay.as_millis().to_string().into()),
+        )
+        .await
+    } else {
+        metrics::counter!(
+            "consumer_messages_total",
+            "queue" => queue_name,
+            "outcome" => "dead_lettered",
+        )
+        .increment(1);
+
+        if poison {
+            error!(
+                queue = queue_name,
+                error = format!("{:#}", err),
+                "A message cannot be handled, moving it to the dead letter queue"
+            );
+        } else {
+            error!(
+                queue = queue_name,
+                retries,
+                error = format!("{:#}", err),
+                "A message failed after its last retry, moving it to the dead letter queue"
+            );
+        }
+
+        let mut headers = headers.clone().unwrap_or_default();
+        headers.insert(
+            ERROR_HEADER.into(),
+            rmq::string_value(&format!("{:#}", err)),
+        );
+        headers.insert(
+            FAILED_AT_HEADER.into(),
+            rmq::string_value(&Utc::now().to_rfc3339()),
+        );
+
+        rmq::publish_confirmed(
+            channel,
+            &rmq::dead_letter_queue(queue_name),
+            &delivery.data,
+            BasicProperties::default().with_headers(headers),
+        )
+        .await
+    }
+}
+
+/// A message that cannot be parsed will not parse on a retry either.
+fn parse<E: Event>(data: &[u8]) -> Result<EventEnvelope<E>, Poison> {
+    let envelope: EventEnvelope<E> = serde_json::from_slice(data).map_err(|e| Poison(e.into()))?;
+
+    if envelope.event_type != E::TYPE {
+        return Err(Poison(anyhow!(
+            "Expected a \"{}\" event but received \"{}\"",
+            E::TYPE,
+            envelope.event_type
+        )));
+    }
+
+    // A payload of another version may not mean what it deserialized into, e.g.
+    // an amount in another unit
+    if envelope.schema_version != E::VERSION {
+        return Err(Poison(anyhow!(
+            "Expected version {} of \"{}\" but received version {}",
+            E::VERSION,
+            E::TYPE,
+            envelope.schema_version
+        )));
+    }
+
+    Ok(envelope)
+}
+
+#[cfg(test)]
+mod tests {
+    use medbook_events::{OrderRejectedEvent, OrderReservedEvent};
+
+    use super::*;
+
+    #[test]
+    fn lane_for_keeps_a_key_on_one_lane() {
+        for lanes in [1, 2, 4, 7, 64] {
+            for order_id in 0..1000 {
+                let key = order_id.to_string();
+                let lane = lane_for(&key, lanes);
+
+                assert!(lane < lanes, "{} is not one of {} lanes", lane, lanes);
+                assert_eq!(lane_for(&key, lanes), lane, "key {}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn lane_for_spreads_keys_over_every_lane() {
+        let mut used = [false; 4];
+        for order_id in 0..1000 {
+            used[lane_for(&order_id.to_string(), used.len())] = true;
+        }
+
+        assert!(used.iter().all(|&used| used));
+    }
+
+    #[test]
+    fn parse_round_trips_an_envelope() {
+        let sent = EventEnvelope::new("orders", OrderReservedEvent { order_id: 7 });
+        let data = serde_json::to_vec(&sent).unwrap();
+
+        let received = parse::<OrderReservedEvent>(&data).unwrap();
+
+        assert_eq!(received.event_id, sent.event_id);
+        assert_eq!(received.event_type, sent.event_type);
+        assert_eq!(received.schema_version, sent.schema_version);
+        assert_eq!(received.occurred_at, sent.occurred_at);
+        assert_eq!(received.correlation_id, sent.correlation_id);
+        assert_eq!(received.causation_id, sent.causation_id);
+        assert_eq!(received.producer, sent.producer);
+        assert_eq!(received.payload.order_id, 7);
+    }
+
+    #[test]
+    fn parse_rejects_another_event_type_as_poison() {
+        // Same payload shape, so only the event type tells them apart
+        let sent = EventEnvelope::new("orders", OrderRejectedEvent { order_id: 7 });
+        let data = serde_json::to_vec(&sent).unwrap();
+
+        let err = anyhow::Error::from(parse::<OrderReservedEvent>(&data).unwrap_err());
+
+        assert!(err.is::<Poison>());
+        assert!(err.to_string().contains("orders.order_rejected"));
+    }
+
+    #[test]
+    fn parse_rejects_another_schema_version_as_poison() {
+        let mut sent = EventEnvelope::new("orders", OrderReservedEvent { order_id: 7 });
+        sent.schema_version = OrderReservedEvent::VERSION + 1;
+        let data = serde_json::to_vec(&sent).unwrap();
+
+        let err = anyhow::Error::from(parse::<OrderReservedEvent>(&data).unwrap_err());
+
+        assert!(err.is::<Poison>());
+        assert!(err.to_string().contains("version"));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_json_as_poison() {
+        let err = parse::<OrderReservedEvent>(b"{\"event_id\":").unwrap_err();
+
+        assert!(anyhow::Error::from(err).is::<Poison>());
+    }
+}
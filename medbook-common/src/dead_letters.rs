@@ -0,0 +1,309 @@
+use anyhow::Context;
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    middleware,
+    response::IntoResponse,
+    routing,
+};
+use lapin::{
+    BasicProperties, Channel,
+    message::BasicGetMessage,
+    options::{BasicAckOptions, BasicGetOptions, BasicNackOptions, ConfirmSelectOptions},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    admin::{AdminToken, admin_authorization},
+    app_error::BaseError,
+    app_state::BaseState,
+    consumers::{self, ERROR_HEADER, FAILED_AT_HEADER, RETRY_COUNT_HEADER},
+    rmq,
+};
+
+/// Most messages a request takes off a dead letter queue at once. Single
+/// messages are only looked up among the first `MAX_PEEK`.
+const MAX_PEEK: usize = 1000;
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Admin routes to list, inspect and replay the messages parked in the dead
+/// letter queues of the queues this service consumes, behind [`AdminToken`].
+pub fn routes<S: BaseState>() -> anyhow::Result<Router<S>> {
+    Ok(Router::new()
+        .route("/", routing::get(list_queues::<S>))
+        .route("/{queue}", routing::get(list_dead_letters::<S>))
+        .route("/{queue}/replay", routing::post(replay_all::<S>))
+        .route("/{queue}/{event_id}", routing::get(get_dead_letter::<S>))
+        .route(
+            "/{queue}/{event_id}/replay",
+            routing::post(replay_dead_letter::<S>),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            AdminToken::from_env()?,
+            admin_authorization,
+        )))
+}
+
+#[derive(Serialize, Debug)]
+struct DeadLetterQueue {
+    queue: String,
+    dead_letter_queue: String,
+    messages: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct DeadLetter {
+    event_id: Option<Uuid>,
+    event_type: Option<String>,
+    retries: i64,
+    error: Option<String>,
+    failed_at: Option<String>,
+    /// The message body, or the raw text when it is not valid JSON.
+    payload: Value,
+}
+
+impl DeadLetter {
+    fn from_message(message: &BasicGetMessage) -> Self {
+        let headers = message.delivery.properties.headers();
+        let payload = serde_json::from_slice(&message.delivery.data).unwrap_or_else(|_| {
+            Value::String(String::from_utf8_lossy(&message.delivery.data).into_owned())
+        });
+
+        Self {
+            event_id: payload
+                .get("event_id")
+                .and_then(Value::as_str)
+                .and_then(|id| id.parse().ok()),
+            event_type: payload
+                .get("event_type")
+                .and_then(Value::as_str)
+                .map(Into::into),
+            retries: rmq::int_header(headers, RETRY_COUNT_HEADER).unwrap_or(0),
+            error: rmq::string_header(headers, ERROR_HEADER),
+            failed_at: rmq::string_header(headers, FAILED_AT_HEADER),
+            payload,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ListDeadLettersQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct DeadLetterPage {
+    dead_letters: Vec<DeadLetter>,
+    offset: usize,
+    limit: usize,
+    /// Messages in the dead letter queue, as counted by the broker.
+    total: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct ReplayReport {
+    replayed: usize,
+}
+
+async fn list_queues<S: BaseState>(State(state): State<S>) -> Result<impl IntoResponse, BaseError> {
+    let channel = state.rmq_client().create_channel().await?;
+    let mut queues = vec![];
+
+    for queue in consumers::queues() {
+        let dead_letter_queue = rmq::declare_dead_letter_queue(&channel, queue).await?;
+
+        queues.push(DeadLetterQueue {
+            queue: queue.into(),
+            dead_letter_queue: dead_letter_queue.name().to_string(),
+            messages: dead_letter_queue.message_count(),
+        });
+    }
+
+    Ok(Json(queues))
+}
+
+async fn list_dead_letters<S: BaseState>(
+    Path(queue): Path<String>,
+    Query(query): Query<ListDeadLettersQuery>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, BaseError> {
+    let queue = consumed_queue(&queue)?;
+    let limit = query.limit.unwrap_or(50).clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.unwrap_or(0).min(MAX_PEEK - limit);
+    let channel = state.rmq_client().create_channel().await?;
+
+    let total = rmq::declare_dead_letter_queue(&channel, queue)
+        .await?
+        .message_count();
+    let messages = peek(&channel, queue, offset + limit).await?;
+    let dead_letters: Vec<_> = messages
+        .iter()
+        .skip(offset)
+        .map(DeadLetter::from_message)
+        .collect();
+    requeue(&channel, &messages).await?;
+
+    Ok(Json(DeadLetterPage {
+        dead_letters,
+        offset,
+        limit,
+        total,
+    }))
+}
+
+async fn get_dead_letter<S: BaseState>(
+    Path((queue, event_id)): Path<(String, Uuid)>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, BaseError> {
+    let queue = consumed_queue(&queue)?;
+    let channel = state.rmq_client().create_channel().await?;
+
+    let messages = peek(&channel, queue, MAX_PEEK).await?;
+    let dead_letter = messages
+        .iter()
+        .map(DeadLetter::from_message)
+        .find(|dead_letter| dead_letter.event_id == Some(event_id));
+    requeue(&channel, &messages).await?;
+
+    match dead_letter {
+        Some(dead_letter) => Ok(Json(dead_letter)),
+        None => Err(BaseError::NotFound(format!("dead letter {}", event_id))),
+    }
+}
+
+async fn replay_dead_letter<S: BaseState>(
+    Path((queue, event_id)): Path<(String, Uuid)>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, BaseError> {
+    let queue = consumed_queue(&queue)?;
+    let channel = replay_channel(&state).await?;
+
+    let messages = peek(&channel, queue, MAX_PEEK).await?;
+    let position = messages
+        .iter()
+        .position(|message| DeadLetter::from_message(message).event_id == Some(event_id));
+
+    let Some(position) = position else {
+        requeue(&channel, &messages).await?;
+        return Err(BaseError::NotFound(format!("dead letter {}", event_id)));
+    };
+
+    for (i, message) in messages.iter().enumerate() {
+        if i == position {
+            replay(&channel, queue, message).await?;
+        } else {
+            requeue(&channel, std::slice::from_ref(message)).await?;
+        }
+    }
+
+    Ok(Json(DeadLetter::from_message(&messages[position])))
+}
+
+async fn replay_all<S: BaseState>(
+    Path(queue): Path<String>,
+    State(state): State<S>,
+) -> Result<impl IntoResponse, BaseError> {
+    let queue = consumed_queue(&queue)?;
+    let channel = replay_channel(&state).await?;
+
+    // Only what is there now: replayed messages that fail again land back in the
+    // dead letter queue and must not be picked up a second time
+    let total = rmq::declare_dead_letter_queue(&channel, queue)
+        .await?
+        .message_count() as usize;
+    let mut replayed = 0;
+
+    while replayed < total {
+        let messages = peek(&channel, queue, (total - replayed).min(MAX_PAGE_SIZE)).await?;
+        if messages.is_empty() {
+            break;
+        }
+        for message in &messages {
+            replay(&channel, queue, message).await?;
+        }
+        replayed += messages.len();
+    }
+
+    Ok(Json(ReplayReport { replayed }))
+}
+
+/// Only the queues consumed by this service can be browsed.
+fn consumed_queue(queue: &str) -> Result<&'static str, BaseError> {
+    consumers::queues()
+        .into_iter()
+        .find(|consumed| *consumed == queue)
+        .ok_or_else(|| BaseError::NotFound(format!("queue {}", queue)))
+}
+
+async fn replay_channel<S: BaseState>(state: &S) -> anyhow::Result<Channel> {
+    let channel = state.rmq_client().create_channel().await?;
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+    Ok(channel)
+}
+
+/// Takes up to `limit` messages off `queue`'s dead letter queue without acking
+/// them; every message must then be either replayed or requeued.
+async fn peek(
+    channel: &Channel,
+    queue: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<BasicGetMessage>> {
+    let dead_letter_queue = rmq::dead_letter_queue(queue);
+    rmq::declare_dead_letter_queue(channel, queue).await?;
+    let mut messages = vec![];
+
+    while messages.len() < limit {
+        let message = channel
+            .basic_get(dead_letter_queue.as_str(), BasicGetOptions::default())
+            .await
+            .context("Failed to read the dead letter queue")?;
+
+        match message {
+            Some(message) => messages.push(message),
+            None => break,
+        }
+    }
+
+    Ok(messages)
+}
+
+async fn requeue(channel: &Channel, messages: &[BasicGetMessage]) -> anyhow::Result<()> {
+    for message in messages {
+        channel
+            .basic_nack(
+                message.delivery.delivery_tag,
+                BasicNackOptions {
+                    requeue: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Publishes a dead letter back to its queue with a fresh retry count.
+async fn replay(channel: &Channel, queue: &str, message: &BasicGetMessage) -> anyhow::Result<()> {
+    rmq::publish_confirmed(
+        channel,
+        queue,
+        &message.delivery.data,
+        BasicProperties::default(),
+    )
+    .await?;
+    message.delivery.ack(BasicAckOptions::default()).await?;
+
+    info!(
+        "Dead letter #{} replayed to \"{}\"",
+        message.delivery.delivery_tag, queue
+    );
+
+    Ok(())
+}
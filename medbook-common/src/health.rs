@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use axum::{Json, Router, extract::State, http::StatusCode, response::IntoResponse, routing};
+use chrono::{DateTime, Utc};
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::Serialize;
+
+use crate::{
+    app_state::BaseState,
+    config::env_or,
+    consumers::{self, ConsumerState, ConsumerStatus},
+    schema::outbox,
+};
+
+/// `/live` tells the orchestrator the process is up, `/ready` whether it should
+/// receive traffic.
+pub fn routes<S: BaseState>() -> Router<S> {
+    Router::new()
+        .route("/live", routing::get(live))
+        .route("/ready", routing::get(ready::<S>))
+}
+
+#[derive(Serialize, Debug)]
+struct ReadinessReport {
+    ready: bool,
+    shutting_down: bool,
+    database: Check,
+    rabbitmq: Check,
+    consumers: Vec<ConsumerStatus>,
+    outbox: OutboxCheck,
+}
+
+#[derive(Serialize, Debug)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn from_result(result: anyhow::Result<()>) -> Self {
+        match result {
+            Ok(_) => Self {
+                ok: true,
+                error: None,
+            },
+            Err(e) => Self {
+                ok: false,
+                error: Some(format!("{:#}", e)),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct OutboxCheck {
+    ok: bool,
+    /// Age of the oldest row still waiting to be published, outside of retry backoff.
+    oldest_pending_age_secs: Option<i64>,
+    max_pending_age_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn live() -> impl IntoResponse {
+    Json(serde_json::json!({ "alive": true }))
+}
+
+async fn ready<S: BaseState>(State(state): State<S>) -> impl IntoResponse {
+    // Rows older than this mean the relay is stuck, e.g. RabbitMQ keeps rejecting them.
+    let max_pending_age = env_or("HEALTH_MAX_OUTBOX_AGE_SECS", 300).unwrap_or(300);
+
+    let database = Check::from_result(check_database(&state).await);
+
+    let rabbitmq = Check::from_result(if state.rmq_client().is_connected() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("RabbitMQ connection is not open"))
+    });
+
+    let consumers = consumers::statuses();
+    let consumers_ok = consumers
+        .iter()
+        .all(|consumer| matches!(consumer.state, ConsumerState::Running));
+
+    let outbox = match oldest_pending(&state).await {
+        Ok(oldest) => {
+            let age = oldest.map(|created_at| (Utc::now() - created_at).num_seconds());
+            OutboxCheck {
+                ok: age.is_none_or(|age| age <= max_pending_age as i64),
+                oldest_pending_age_secs: age,
+                max_pending_age_secs: max_pending_age,
+                error: None,
+            }
+        }
+        Err(e) => OutboxCheck {
+            ok: false,
+            oldest_pending_age_secs: None,
+            max_pending_age_secs: max_pending_age,
+            error: Some(format!("{:#}", e)),
+        },
+    };
+
+    let shutting_down = state.shutdown().is_cancelled();
+    let ready = !shutting_down && database.ok && rabbitmq.ok && consumers_ok && outbox.ok;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(ReadinessReport {
+            ready,
+            shutting_down,
+            database,
+            rabbitmq,
+            consumers,
+            outbox,
+        }),
+    )
+}
+
+/// How long a probe may wait, including for a connection when the pool is full.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+async fn check_database<S: BaseState>(state: &S) -> anyhow::Result<()> {
+    tokio::time::timeout(PROBE_TIMEOUT, async {
+        let conn = &mut state.db_pool().get().await?;
+        diesel::sql_query("SELECT 1").execute(conn).await?;
+        Ok(())
+    })
+    .await?
+}
+
+/// Creation time of the oldest row the relay should already have published.
+/// Rows waiting out a retry backoff are left out: a single event RabbitMQ keeps
+/// rejecting must not take every replica out of rotation.
+async fn oldest_pending<S: BaseState>(state: &S) -> anyhow::Result<Option<DateTime<Utc>>> {
+    tokio::time::timeout(PROBE_TIMEOUT, async {
+        let conn = &mut state.db_pool().get().await?;
+        let oldest = outbox::table
+            .filter(outbox::status.eq("PENDING"))
+            .filter(outbox::next_attempt_at.le(diesel::dsl::now))
+            .select(diesel::dsl::min(outbox::created_at))
+            .first(conn)
+            .await?;
+        Ok(oldest)
+    })
+    .await?
+}
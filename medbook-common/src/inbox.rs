@@ -0,0 +1,36 @@
+use anyhow::Context;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use medbook_events::{Event, EventEnvelope};
+use tracing::info;
+
+use crate::{models::CreateInboxEntity, schema::inbox};
+
+/// Marks `envelope` as consumed. Returns `false` if it already was, in which case
+/// the handler should skip its side effects.
+///
+/// Call it inside the handler's transaction: the record then only sticks if the
+/// side effects are committed with it.
+pub async fn record<E: Event>(
+    conn: &mut AsyncPgConnection,
+    envelope: &EventEnvelope<E>,
+) -> anyhow::Result<bool> {
+    let inserted = diesel::insert_into(inbox::table)
+        .values(CreateInboxEntity {
+            event_id: envelope.event_id,
+            event_type: E::TYPE.into(),
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await
+        .context("Inbox record failed")?;
+
+    if inserted == 0 {
+        info!(
+            "Event {} ({}) has already been processed, skipping",
+            envelope.event_id,
+            E::TYPE
+        );
+    }
+
+    Ok(inserted == 1)
+}
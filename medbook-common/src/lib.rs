@@ -0,0 +1,17 @@
+pub mod admin;
+pub mod app_error;
+pub mod app_state;
+pub mod config;
+pub mod consumers;
+pub mod db;
+pub mod dead_letters;
+pub mod health;
+pub mod inbox;
+pub mod models;
+pub mod outbox;
+pub mod prometheus;
+pub mod rmq;
+pub mod schema;
+pub mod shutdown;
+pub mod stage;
+pub mod telemetry;
@@ -0,0 +1,39 @@
+use chrono::{DateTime, Utc};
+use diesel::{
+    Selectable,
+    prelude::{Insertable, Queryable},
+};
+use uuid::Uuid;
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::outbox)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OutboxEntity {
+    pub id: i32,
+    pub event_type: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub processed_at: Option<DateTime<Utc>>,
+    pub trace_context: Option<String>,
+}
+
+#[derive(Queryable, Insertable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::outbox)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreateOutboxEntity {
+    pub event_type: String,
+    pub payload: String,
+    pub trace_context: Option<String>,
+}
+
+#[derive(Queryable, Insertable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::inbox)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreateInboxEntity {
+    pub event_id: Uuid,
+    pub event_type: String,
+}
@@ -0,0 +1,576 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+use chrono::{TimeDelta, Utc};
+use diesel::{
+    ExpressionMethods, QueryDsl, SelectableHelper,
+    dsl::now,
+    sql_types::{BigInt, Double},
+};
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use futures::StreamExt;
+use lapin::{BasicProperties, Channel, options::ConfirmSelectOptions, types::FieldTable};
+use medbook_events::{Event, EventEnvelope};
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tokio_postgres::{AsyncMessage, NoTls};
+use tracing::{Instrument, debug, error, info, info_span, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::{
+    app_state::BaseState,
+    config::env_or,
+    models::{CreateOutboxEntity, OutboxEntity},
+    rmq,
+    schema::outbox,
+    telemetry,
+};
+
+/// Stores `envelope` in the outbox; the relay later publishes it on `E::TYPE`,
+/// continuing the trace of the current span.
+pub async fn publish<E: Event>(
+    conn: &mut AsyncPgConnection,
+    envelope: &EventEnvelope<E>,
+) -> anyhow::Result<OutboxEntity> {
+    let trace_context = telemetry::current_context();
+
+    let outbox = diesel::insert_into(outbox::table)
+        .values(CreateOutboxEntity {
+            event_type: E::TYPE.into(),
+            payload: serde_json::to_string(envelope)
+                .with_context(|| format!("Failed to serialize \"{}\" event", E::TYPE))?,
+            trace_context: if trace_context.is_empty() {
+                None
+            } else {
+                Some(serde_json::to_string(&trace_context)?)
+            },
+        })
+        .returning(OutboxEntity::as_returning())
+        .get_result(conn)
+        .await
+        .context("Outbox creation failed")?;
+
+    info!(
+        outbox_id = outbox.id,
+        event_type = E::TYPE,
+        event_id = %envelope.event_id,
+        "Outbox event created"
+    );
+
+    Ok(outbox)
+}
+
+/// Postgres channel the `outbox_notify` trigger sends on after every insert.
+const NOTIFY_CHANNEL: &str = "outbox_inserted";
+
+/// Relay settings, read from `OUTBOX_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    /// Database the relay `LISTEN`s on; the same one the pool connects to.
+    pub database_url: String,
+    /// Maximum number of rows one relay instance claims per transaction.
+    pub batch_size: i64,
+    /// Fallback poll when no notification arrives, e.g. while the listener reconnects.
+    pub poll_interval: Duration,
+    /// Failed publishes after which a row is marked `FAILED` and left alone.
+    pub max_attempts: i32,
+    /// Delay after the first failed publish, doubled after every further failure.
+    pub backoff_base: Duration,
+    /// Upper bound for the delay between two attempts.
+    pub backoff_max: Duration,
+    /// How long `PROCESSED` rows are kept in `outbox` before the retention job
+    /// removes them.
+    pub retention: Duration,
+    /// How often the retention job runs.
+    pub retention_interval: Duration,
+    /// Move expired rows to `outbox_archive` instead of deleting them.
+    pub archive: bool,
+}
+
+impl OutboxConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            database_url: std::env::var("DATABASE_URL").context("DATABASE_URL is invalid")?,
+            batch_size: env_or("OUTBOX_BATCH_SIZE", 100)?,
+            poll_interval: Duration::from_secs(env_or("OUTBOX_POLL_INTERVAL_SECS", 5)?),
+            max_attempts: env_or("OUTBOX_MAX_ATTEMPTS", 10)?,
+            backoff_base: Duration::from_secs(env_or("OUTBOX_BACKOFF_BASE_SECS", 1)?),
+            backoff_max: Duration::from_secs(env_or("OUTBOX_BACKOFF_MAX_SECS", 300)?),
+            retention: Duration::from_secs(env_or("OUTBOX_RETENTION_SECS", 7 * 24 * 60 * 60)?),
+            retention_interval: Duration::from_secs(env_or(
+                "OUTBOX_RETENTION_INTERVAL_SECS",
+                60 * 60,
+            )?),
+            archive: env_or("OUTBOX_ARCHIVE", true)?,
+        };
+
+        // A batch of 0 would never claim a row, and make the retention job loop
+        if config.batch_size <= 0 {
+            bail!(
+                "OUTBOX_BATCH_SIZE must be positive, got {}",
+                config.batch_size
+            );
+        }
+        if config.max_attempts <= 0 {
+            bail!(
+                "OUTBOX_MAX_ATTEMPTS must be positive, got {}",
+                config.max_attempts
+            );
+        }
+
+        Ok(config)
+    }
+
+    /// Delay before the next attempt once `attempts` publishes have failed.
+    fn backoff(&self, attempts: i32) -> Duration {
+        let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+        self.backoff_base
+            .saturating_mul(2u32.pow(exponent))
+            .min(self.backoff_max)
+    }
+}
+
+/// Spawns the relay that publishes pending outbox rows to RabbitMQ.
+///
+/// Rows are claimed with `FOR UPDATE SKIP LOCKED`, so several replicas of the
+/// same service can run their relays side by side without double-publishing.
+/// The relay is woken by `NOTIFY` as soon as a row is inserted, and polls every
+/// `poll_interval` in case a notification is missed. Every task stops once the
+/// service shuts down.
+pub fn init<S: BaseState>(state: S, config: OutboxConfig) {
+    info!("Outbox initialized");
+    let wakeup = Arc::new(Notify::new());
+    let shutdown = state.shutdown().clone();
+
+    let database_url = config.database_url.clone();
+    let listener_wakeup = wakeup.clone();
+    let listener_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        while !listener_shutdown.is_cancelled() {
+            tokio::select! {
+                result = listen(&database_url, &listener_wakeup) => {
+                    if let Err(e) = result {
+                        error!(error = format!("{:#}", e), "Outbox listener failed");
+                    }
+                }
+                _ = listener_shutdown.cancelled() => break,
+            }
+            warn!(
+                retry_in_secs = 5,
+                "Outbox listener disconnected, reconnecting"
+            );
+            listener_shutdown.sleep(Duration::from_secs(5)).await;
+        }
+    });
+
+    let retention_state = state.clone();
+    let retention_config = config.clone();
+    let retention_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        while !retention_shutdown.is_cancelled() {
+            if let Err(e) = purge(&retention_state, &retention_config).await {
+                error!(error = format!("{:#}", e), "Outbox retention job failed");
+            }
+            retention_shutdown
+                .sleep(retention_config.retention_interval)
+                .await;
+        }
+    });
+
+    let relay_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        while !relay_shutdown.is_cancelled() {
+            if let Err(e) = start(state.clone(), &config, &wakeup).await {
+                error!(
+                    error = format!("{:#}", e),
+                    retry_in_secs = 5,
+                    "Outbox relay failed, restarting"
+                );
+                relay_shutdown.sleep(Duration::from_secs(5)).await;
+            }
+        }
+        info!("Outbox relay stopped");
+    });
+}
+
+/// Holds a dedicated connection that `LISTEN`s on [`NOTIFY_CHANNEL`] and wakes
+/// the relay for every notification. Returns once the connection is lost.
+async fn listen(database_url: &str, wakeup: &Notify) -> anyhow::Result<()> {
+    let (client, mut connection) = tokio_postgres::connect(database_url, NoTls)
+        .await
+        .context("Outbox listener connection failed")?;
+
+    // The connection only delivers notifications while it is being polled, and
+    // it must be polled for `LISTEN` below to complete as well.
+    let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let driver = tokio::spawn(async move {
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(_)) => {
+                    if tx.send(()).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    });
+
+    client
+        .batch_execute(&format!("LISTEN {}", NOTIFY_CHANNEL))
+        .await
+        .context("LISTEN failed")?;
+    info!(
+        channel = NOTIFY_CHANNEL,
+        "Outbox listening for notifications"
+    );
+
+    // Rows may have been inserted while the listener was down.
+    wakeup.notify_one();
+
+    while rx.recv().await.is_some() {
+        wakeup.notify_one();
+    }
+
+    driver.await??;
+    Ok(())
+}
+
+async fn start<S: BaseState>(
+    state: S,
+    config: &OutboxConfig,
+    wakeup: &Notify,
+) -> anyhow::Result<()> {
+    let channel = state.rmq_client().create_channel().await?;
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+    let conn = &mut state.db_pool().get().await?;
+    let shutdown = state.shutdown();
+
+    // A batch that has started is always published and committed before the
+    // relay stops.
+    while !shutdown.is_cancelled() {
+        // E.g. the broker restarted while the relay was waiting for rows
+        if !channel.status().connected() {
+            bail!("RabbitMQ channel is closed");
+        }
+
+        debug!("Processing outbox");
+
+        let (claimed, channel_error) = conn
+            .transaction(|tx| {
+                let channel = &channel;
+                Box::pin(async move {
+                    // Rows locked by another relay are skipped, and stay locked until
+                    // this transaction commits their new status.
+                    let events: Vec<OutboxEntity> = outbox::table
+                        .filter(outbox::status.eq("PENDING"))
+                        .filter(outbox::next_attempt_at.le(now))
+                        .order(outbox::id.asc())
+                        .limit(config.batch_size)
+                        .for_update()
+                        .skip_locked()
+                        .select(OutboxEntity::as_select())
+                        .get_results(tx)
+                        .await?;
+
+                    for event in &events {
+                        let start = Instant::now();
+                        match publish_event(channel, event).await {
+                            Ok(_) => {
+                                record_published(event, start);
+                                diesel::update(outbox::table.filter(outbox::id.eq(event.id)))
+                                    .set((
+                                        outbox::status.eq("PROCESSED"),
+                                        outbox::processed_at.eq(now),
+                                    ))
+                                    .execute(tx)
+                                    .await?;
+                                info!(
+                                    outbox_id = event.id,
+                                    event_type = event.event_type,
+                                    event_id = event_id(event),
+                                    "Outbox event published"
+                                )
+                            }
+                            // The broker or the connection went away, which is not the
+                            // fault of this row nor of the ones after it. They stay
+                            // PENDING as they are, and the rows published so far are
+                            // still committed.
+                            Err(e) if !channel.status().connected() => {
+                                return Ok((events.len(), Some(e)));
+                            }
+                            Err(e) => {
+                                metrics::counter!(
+                                    "outbox_publish_failures_total",
+                                    "event_type" => event.event_type.clone(),
+                                )
+                                .increment(1);
+                                record_failure(tx, config, event, &e).await?
+                            }
+                        };
+                    }
+
+                    Ok::<_, anyhow::Error>((events.len(), None))
+                })
+            })
+            .await?;
+
+        // Restarts the relay on a new channel
+        if let Some(e) = channel_error {
+            return Err(e.context("RabbitMQ channel closed while publishing"));
+        }
+
+        if claimed == 0 {
+            debug!(
+                poll_interval_secs = config.poll_interval.as_secs(),
+                "No events to process, waiting for a notification"
+            );
+            tokio::select! {
+                _ = wakeup.notified() => {}
+                _ = shutdown.sleep(config.poll_interval) => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Succeeds only once the broker has acked the message, so a row is never marked
+/// `PROCESSED` for a message RabbitMQ did not persist.
+async fn publish_event(channel: &Channel, event: &OutboxEntity) -> anyhow::Result<()> {
+    let span = info_span!(
+        "outbox_publish",
+        otel.name = format!("{} publish", event.event_type),
+        otel.kind = "producer",
+        outbox_id = event.id,
+        event_type = event.event_type,
+        event_id = event_id(event),
+    );
+    if let Some(trace_context) = &event.trace_context {
+        span.set_parent(telemetry::context_from(&serde_json::from_str(
+            trace_context,
+        )?));
+    }
+
+    let mut headers = FieldTable::default();
+    telemetry::inject_headers(&span, &mut headers);
+
+    async {
+        rmq::declare_queue(channel, &event.event_type).await?;
+        rmq::publish_confirmed(
+            channel,
+            &event.event_type,
+            event.payload.as_bytes(),
+            BasicProperties::default().with_headers(headers),
+        )
+        .await
+    }
+    .instrument(span)
+    .await
+}
+
+/// The `event_id` of the envelope in `event`'s payload, for logs.
+fn event_id(event: &OutboxEntity) -> Option<String> {
+    #[derive(Deserialize)]
+    struct EnvelopeId {
+        event_id: String,
+    }
+
+    serde_json::from_str::<EnvelopeId>(&event.payload)
+        .ok()
+        .map(|envelope| envelope.event_id)
+}
+
+/// `outbox_publish_lag_seconds` is how long the event waited in the outbox, from
+/// insert to broker ack.
+fn record_published(event: &OutboxEntity, start: Instant) {
+    let lag = (Utc::now() - event.created_at).to_std().unwrap_or_default();
+
+    metrics::counter!("outbox_published_total", "event_type" => event.event_type.clone())
+        .increment(1);
+    metrics::histogram!(
+        "outbox_publish_duration_seconds",
+        "event_type" => event.event_type.clone(),
+    )
+    .record(start.elapsed());
+    metrics::histogram!("outbox_publish_lag_seconds", "event_type" => event.event_type.clone())
+        .record(lag);
+}
+
+/// Schedules the next attempt with exponential backoff, or gives up on the row
+/// once it has failed `max_attempts` times.
+async fn record_failure(
+    conn: &mut AsyncPgConnection,
+    config: &OutboxConfig,
+    event: &OutboxEntity,
+    err: &anyhow::Error,
+) -> anyhow::Result<()> {
+    let attempts = event.attempts + 1;
+    let last_error = format!("{:#}", err);
+
+    if attempts >= config.max_attempts {
+        diesel::update(outbox::table.filter(outbox::id.eq(event.id)))
+            .set((
+                outbox::status.eq("FAILED"),
+                outbox::attempts.eq(attempts),
+                outbox::last_error.eq(&last_error),
+            ))
+            .execute(conn)
+            .await?;
+
+        error!(
+            outbox_id = event.id,
+            event_type = event.event_type,
+            event_id = event_id(event),
+            attempts,
+            error = last_error,
+            "Outbox event marked as FAILED, giving up"
+        );
+    } else {
+        let delay = config.backoff(attempts);
+
+        diesel::update(outbox::table.filter(outbox::id.eq(event.id)))
+            .set((
+                outbox::attempts.eq(attempts),
+                outbox::last_error.eq(&last_error),
+                outbox::next_attempt_at.eq(Utc::now() + TimeDelta::from_std(delay)?),
+            ))
+            .execute(conn)
+            .await?;
+
+        error!(
+            outbox_id = event.id,
+            event_type = event.event_type,
+            event_id = event_id(event),
+            attempts,
+            max_attempts = config.max_attempts,
+            retry_in_secs = delay.as_secs(),
+            error = last_error,
+            "Failed to publish outbox event, retrying"
+        );
+    }
+
+    Ok(())
+}
+
+/// Deletes, or archives, `PROCESSED` rows older than `retention`, one batch per
+/// transaction so the job never holds many row locks at once.
+async fn purge<S: BaseState>(state: &S, config: &OutboxConfig) -> anyhow::Result<()> {
+    let query = if config.archive {
+        "WITH expired AS (
+            DELETE FROM outbox
+            WHERE id IN (
+                SELECT id FROM outbox
+                WHERE status = 'PROCESSED' AND processed_at < NOW() - make_interval(secs => $1)
+                ORDER BY id
+                LIMIT $2
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, event_type, payload, status, attempts, last_error, created_at, processed_at,
+                trace_context
+        )
+        INSERT INTO outbox_archive
+            (id, event_type, payload, status, attempts, last_error, created_at, processed_at,
+                trace_context)
+        SELECT * FROM expired"
+    } else {
+        "DELETE FROM outbox
+        WHERE id IN (
+            SELECT id FROM outbox
+            WHERE status = 'PROCESSED' AND processed_at < NOW() - make_interval(secs => $1)
+            ORDER BY id
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+        )"
+    };
+
+    let conn = &mut state.db_pool().get().await?;
+    let mut total = 0;
+
+    loop {
+        let removed = diesel::sql_query(query)
+            .bind::<Double, _>(config.retention.as_secs_f64())
+            .bind::<BigInt, _>(config.batch_size)
+            .execute(conn)
+            .await
+            .context("Outbox retention query failed")?;
+
+        total += removed;
+        if (removed as i64) < config.batch_size {
+            break;
+        }
+    }
+
+    if total > 0 {
+        info!(
+            events = total,
+            archived = config.archive,
+            "Outbox retention removed processed events"
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(backoff_base: u64, backoff_max: u64) -> OutboxConfig {
+        OutboxConfig {
+            database_url: String::new(),
+            batch_size: 100,
+            poll_interval: Duration::from_secs(5),
+            max_attempts: 10,
+            backoff_base: Duration::from_secs(backoff_base),
+            backoff_max: Duration::from_secs(backoff_max),
+            retention: Duration::from_secs(60),
+            retention_interval: Duration::from_secs(60),
+            archive: true,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let config = config(1, 300);
+
+        // (failed attempts, delay in seconds)
+        let schedule = [
+            (0, 1),
+            (1, 1),
+            (2, 2),
+            (3, 4),
+            (4, 8),
+            (5, 16),
+            (8, 128),
+            (9, 256),
+            (10, 300),
+            (20, 300),
+            (i32::MAX, 300),
+        ];
+
+        for (attempts, delay) in schedule {
+            assert_eq!(
+                config.backoff(attempts),
+                Duration::from_secs(delay),
+                "after {} attempts",
+                attempts
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_does_not_overflow_a_large_base() {
+        let config = config(u64::MAX / 2, u64::MAX);
+
+        assert_eq!(config.backoff(17), Duration::from_secs(u64::MAX));
+    }
+}
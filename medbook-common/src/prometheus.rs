@@ -0,0 +1,102 @@
+use std::{sync::OnceLock, time::Instant};
+
+use anyhow::Result;
+use axum::{
+    Router,
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing,
+};
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use tracing::error;
+
+use crate::{app_state::BaseState, schema::outbox};
+
+/// Buckets for every `*_seconds` histogram, from 1ms up to 30s.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Installs the Prometheus recorder. Call it once at startup, before anything
+/// records a metric; metrics recorded earlier are dropped.
+pub fn init() -> Result<()> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".into()), LATENCY_BUCKETS)?
+        .install_recorder()?;
+
+    let _ = HANDLE.set(handle);
+    Ok(())
+}
+
+/// Serves the metrics in the Prometheus text format.
+pub fn routes<S: BaseState>() -> Router<S> {
+    Router::new().route("/", routing::get(render::<S>))
+}
+
+/// Records `http_requests_total` and `http_request_duration_seconds` per route.
+/// Add it with `Router::layer(axum::middleware::from_fn(prometheus::track_http))`.
+pub async fn track_http(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    // The route template, not the actual path, to keep the label set bounded.
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".into());
+
+    let response = next.run(request).await;
+    let status = response.status().as_u16().to_string();
+
+    metrics::counter!(
+        "http_requests_total",
+        "method" => method.clone(),
+        "route" => route.clone(),
+        "status" => status.clone(),
+    )
+    .increment(1);
+    metrics::histogram!(
+        "http_request_duration_seconds",
+        "method" => method,
+        "route" => route,
+        "status" => status,
+    )
+    .record(start.elapsed());
+
+    response
+}
+
+/// Gauges that are cheaper to read on scrape than to keep up to date.
+async fn render<S: BaseState>(State(state): State<S>) -> impl IntoResponse {
+    let pool = state.db_pool().state();
+    metrics::gauge!("db_pool_connections").set(pool.connections);
+    metrics::gauge!("db_pool_idle_connections").set(pool.idle_connections);
+
+    match outbox_backlog(&state).await {
+        Ok(backlog) => metrics::gauge!("outbox_backlog").set(backlog as f64),
+        Err(e) => error!(
+            error = format!("{:#}", e),
+            "Failed to measure the outbox backlog"
+        ),
+    }
+
+    HANDLE
+        .get()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default()
+}
+
+async fn outbox_backlog<S: BaseState>(state: &S) -> Result<i64> {
+    let conn = &mut state.db_pool().get().await?;
+    let backlog = outbox::table
+        .filter(outbox::status.eq("PENDING"))
+        .count()
+        .get_result(conn)
+        .await?;
+    Ok(backlog)
+}
@@ -0,0 +1,184 @@
+use std::sync::{Arc, RwLock};
+
+use anyhow::{Context, Result, bail};
+use lapin::{
+    BasicProperties, Channel, Connection, ConnectionProperties, Queue,
+    options::{BasicPublishOptions, QueueDeclareOptions},
+    types::{AMQPValue, FieldTable},
+};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// AMQP delivery mode that makes the broker write the message to disk.
+const PERSISTENT: u8 = 2;
+
+/// Shared RabbitMQ connection; consumers and the outbox relay each open their
+/// own channel on it.
+///
+/// A connection the broker dropped is replaced the next time a channel is
+/// opened, so consumers and the relay recover once they restart.
+#[derive(Clone)]
+pub struct Rmq {
+    url: Arc<str>,
+    connection: Arc<RwLock<Arc<Connection>>>,
+    /// Held while reconnecting, so only one task opens the new connection.
+    reconnecting: Arc<Mutex<()>>,
+}
+
+impl Rmq {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let connection = open(url).await?;
+
+        Ok(Self {
+            url: url.into(),
+            connection: Arc::new(RwLock::new(Arc::new(connection))),
+            reconnecting: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub async fn create_channel(&self) -> Result<Channel> {
+        Ok(self.connection().await?.create_channel().await?)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.current().status().connected()
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        let connection = self.current();
+        if !connection.status().connected() {
+            return Ok(());
+        }
+
+        connection.close(200, "Service shutting down").await?;
+        info!("RabbitMQ connection closed");
+        Ok(())
+    }
+
+    fn current(&self) -> Arc<Connection> {
+        self.connection.read().unwrap().clone()
+    }
+
+    /// The open connection, reconnecting first if it was lost.
+    async fn connection(&self) -> Result<Arc<Connection>> {
+        let connection = self.current();
+        if connection.status().connected() {
+            return Ok(connection);
+        }
+
+        let _reconnecting = self.reconnecting.lock().await;
+        // Another task may have reconnected while this one waited for the lock
+        let connection = self.current();
+        if connection.status().connected() {
+            return Ok(connection);
+        }
+
+        warn!("RabbitMQ connection lost, reconnecting");
+        let connection = Arc::new(open(&self.url).await?);
+        *self.connection.write().unwrap() = connection.clone();
+        Ok(connection)
+    }
+}
+
+async fn open(url: &str) -> Result<Connection> {
+    let connection = Connection::connect(url, ConnectionProperties::default())
+        .await
+        .context("RabbitMQ connection failed")?;
+    info!("Connected to RabbitMQ");
+    Ok(connection)
+}
+
+/// Declares `name` as a durable queue, so it and its persistent messages
+/// survive a broker restart.
+pub async fn declare_queue(channel: &Channel, name: &str) -> Result<Queue> {
+    declare(channel, name, FieldTable::default()).await
+}
+
+/// Declares the queue that holds `queue`'s messages while they wait to be
+/// retried. Expired messages are dead-lettered straight back to `queue`.
+pub async fn declare_retry_queue(channel: &Channel, queue: &str) -> Result<Queue> {
+    let mut arguments = FieldTable::default();
+    arguments.insert("x-dead-letter-exchange".into(), string_value(""));
+    arguments.insert("x-dead-letter-routing-key".into(), string_value(queue));
+
+    declare(channel, &retry_queue(queue), arguments).await
+}
+
+/// Declares the queue that parks `queue`'s messages that will not be retried.
+pub async fn declare_dead_letter_queue(channel: &Channel, queue: &str) -> Result<Queue> {
+    declare_queue(channel, &dead_letter_queue(queue)).await
+}
+
+pub fn retry_queue(queue: &str) -> String {
+    format!("{}.retry", queue)
+}
+
+pub fn dead_letter_queue(queue: &str) -> String {
+    format!("{}.dlq", queue)
+}
+
+async fn declare(channel: &Channel, name: &str, arguments: FieldTable) -> Result<Queue> {
+    channel
+        .queue_declare(
+            name,
+            QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            arguments,
+        )
+        .await
+        .with_context(|| format!("Failed to declare queue \"{}\"", name))
+}
+
+/// Publishes `payload` to `queue` as a persistent message and waits until the
+/// broker confirms it. `channel` must have been put in confirm mode.
+pub async fn publish_confirmed(
+    channel: &Channel,
+    queue: &str,
+    payload: &[u8],
+    properties: BasicProperties,
+) -> Result<()> {
+    let confirmation = channel
+        .basic_publish(
+            "",
+            queue,
+            BasicPublishOptions::default(),
+            payload,
+            properties.with_delivery_mode(PERSISTENT),
+        )
+        .await?
+        .await?;
+
+    if !confirmation.is_ack() {
+        bail!("Broker did not ack the message published to \"{}\"", queue);
+    }
+
+    Ok(())
+}
+
+pub fn string_value(value: &str) -> AMQPValue {
+    AMQPValue::LongString(value.into())
+}
+
+/// Reads a header set with [`string_value`].
+pub fn string_header(headers: &Option<FieldTable>, key: &str) -> Option<String> {
+    match headers.as_ref()?.inner().get(key)? {
+        AMQPValue::LongString(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Reads an integer header, whichever integer type the publisher used.
+pub fn int_header(headers: &Option<FieldTable>, key: &str) -> Option<i64> {
+    match headers.as_ref()?.inner().get(key)? {
+        AMQPValue::ShortShortInt(value) => Some(*value as i64),
+        AMQPValue::ShortShortUInt(value) => Some(*value as i64),
+        AMQPValue::ShortInt(value) => Some(*value as i64),
+        AMQPValue::ShortUInt(value) => Some(*value as i64),
+        AMQPValue::LongInt(value) => Some(*value as i64),
+        AMQPValue::LongUInt(value) => Some(*value as i64),
+        AMQPValue::LongLongInt(value) => Some(*value),
+        _ => None,
+    }
+}
@@ -0,0 +1,41 @@
+// Tables every service owns a copy of, in its own database.
+
+diesel::table! {
+    inbox (event_id) {
+        event_id -> Uuid,
+        event_type -> Text,
+        processed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    outbox (id) {
+        id -> Int4,
+        event_type -> Text,
+        payload -> Text,
+        status -> Text,
+        attempts -> Int4,
+        last_error -> Nullable<Text>,
+        next_attempt_at -> Timestamptz,
+        created_at -> Timestamptz,
+        processed_at -> Nullable<Timestamptz>,
+        trace_context -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    outbox_archive (id) {
+        id -> Int4,
+        event_type -> Text,
+        payload -> Text,
+        status -> Text,
+        attempts -> Int4,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        processed_at -> Nullable<Timestamptz>,
+        archived_at -> Timestamptz,
+        trace_context -> Nullable<Text>,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(inbox, outbox, outbox_archive,);
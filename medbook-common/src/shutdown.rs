@@ -0,0 +1,115 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    task::AbortHandle,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, warn};
+
+use crate::{app_state::BaseState, config::env_or, telemetry};
+
+/// Coordinates a graceful shutdown of the background tasks of a service.
+///
+/// Consumers and the outbox relay are spawned on [`Shutdown::spawn`] and stop
+/// taking new work once [`Shutdown::signal`] resolves; [`drain`] then waits for
+/// the work they already started.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+    /// Lets [`drain`] abort the tasks that outlive its timeout.
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let task = self.tracker.spawn(future);
+        self.tasks.lock().unwrap().push(task.abort_handle());
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    /// A token cancelled along with the service, that can also be cancelled on its
+    /// own to stop a single task.
+    pub fn child_token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+
+    /// Sleeps for `duration`, or less if the service is shutting down.
+    pub async fn sleep(&self, duration: Duration) {
+        tokio::select! {
+            _ = self.token.cancelled() => {}
+            _ = tokio::time::sleep(duration) => {}
+        }
+    }
+
+    /// Resolves on SIGTERM or Ctrl+C and tells every task to stop. Meant for
+    /// `axum::serve(..).with_graceful_shutdown`.
+    pub async fn signal(self) {
+        let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+            _ = self.token.cancelled() => {}
+        }
+
+        info!("Shutting down...");
+        self.token.cancel();
+    }
+}
+
+/// Waits for in-flight consumer and outbox work to finish, up to
+/// `SHUTDOWN_TIMEOUT_SECS`, and aborts the tasks still running after that. Then
+/// closes the RabbitMQ connection and the database pool.
+///
+/// `state` must be the last clone of the service state, so that dropping it
+/// drops the pool and closes its connections; the HTTP server and every task
+/// holding another clone are done by then.
+pub async fn drain<S: BaseState>(state: S) -> Result<()> {
+    let timeout = Duration::from_secs(env_or("SHUTDOWN_TIMEOUT_SECS", 30)?);
+    let shutdown = state.shutdown().clone();
+    shutdown.tracker.close();
+
+    if tokio::time::timeout(timeout, shutdown.tracker.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            tasks = shutdown.tracker.len(),
+            timeout_secs = timeout.as_secs(),
+            "Background tasks still running after the shutdown timeout, aborting them"
+        );
+        for task in shutdown.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        // Aborted tasks drop their work, and their clone of the state, right away
+        shutdown.tracker.wait().await;
+    }
+
+    state.rmq_client().close().await?;
+    drop(state);
+    info!("Database pool closed");
+    telemetry::shutdown()?;
+    info!("Shutdown complete");
+
+    Ok(())
+}
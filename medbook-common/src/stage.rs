@@ -0,0 +1,48 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    Local,
+    Development,
+    Production,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            Stage::Local => "Local",
+            Stage::Development => "Development",
+            Stage::Production => "Production",
+        };
+
+        write!(f, "{}", stage)
+    }
+}
+
+impl Stage {
+    pub fn try_from(stage: &str) -> Result<Self> {
+        match stage {
+            "Local" => Ok(Stage::Local),
+            "Development" => Ok(Stage::Development),
+            "Production" => Ok(Stage::Production),
+            _ => Err(anyhow::anyhow!("Invalid stage \"{}\"", stage)),
+        }
+    }
+
+    /// The stage of `STAGE`, Production when it is unset so test tools stay off
+    /// unless asked for. An invalid one is an error rather than a guess.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("STAGE") {
+            Ok(stage) => Stage::try_from(&stage).context("STAGE is invalid"),
+            Err(_) => Ok(Stage::Production),
+        }
+    }
+
+    /// Whether tools that bypass the real flows, like settling payments by hand,
+    /// may be exposed.
+    pub fn allows_test_tools(&self) -> bool {
+        matches!(self, Stage::Local | Stage::Development)
+    }
+}
@@ -0,0 +1,214 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use anyhow::{Result, bail};
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use lapin::types::{AMQPValue, FieldTable};
+use opentelemetry::{
+    Context, global,
+    propagation::{Extractor, Injector},
+    trace::TracerProvider,
+};
+use opentelemetry_sdk::{Resource, propagation::TraceContextPropagator, trace::SdkTracerProvider};
+use tracing::{Instrument, Span, info_span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use uuid::Uuid;
+
+use crate::config::env_or;
+
+/// Header carrying the id of a request, set by the caller or generated by [`request_id`].
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Sets up logging and, depending on `TRACING_EXPORTER`, exports spans.
+///
+/// Logs are filtered by `RUST_LOG` (defaults to `info`) and written as one JSON
+/// object per line, with the fields of the enclosing spans, unless `LOG_FORMAT`
+/// is `text`.
+///
+/// Span exporters:
+///
+/// - `otlp`: to the collector at `OTEL_EXPORTER_OTLP_ENDPOINT` (gRPC, defaults
+///   to `http://localhost:4317`).
+/// - `stdout`: printed to the console, for local runs.
+/// - `none` (default): spans are only used for logging.
+///
+/// Trace context is propagated in W3C `traceparent` format, over HTTP headers
+/// and AMQP message headers alike.
+pub fn init(service_name: &'static str) -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let builder = SdkTracerProvider::builder()
+        .with_resource(Resource::builder().with_service_name(service_name).build());
+
+    let provider = match env_or("TRACING_EXPORTER", String::from("none"))?.as_str() {
+        "otlp" => Some(
+            builder
+                .with_batch_exporter(
+                    opentelemetry_otlp::SpanExporter::builder()
+                        .with_tonic()
+                        .build()?,
+                )
+                .build(),
+        ),
+        "stdout" => Some(
+            builder
+                .with_simple_exporter(opentelemetry_stdout::SpanExporter::default())
+                .build(),
+        ),
+        "none" => None,
+        exporter => bail!("TRACING_EXPORTER \"{}\" is invalid", exporter),
+    };
+
+    let json = match env_or("LOG_FORMAT", String::from("json"))?.as_str() {
+        "json" => true,
+        "text" => false,
+        format => bail!("LOG_FORMAT \"{}\" is invalid", format),
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name)));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json.then(|| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(true)
+        }))
+        .with((!json).then(tracing_subscriber::fmt::layer))
+        .with(otel_layer)
+        .init();
+
+    if let Some(provider) = provider {
+        global::set_tracer_provider(provider.clone());
+        let _ = PROVIDER.set(provider);
+    }
+
+    Ok(())
+}
+
+/// Flushes the spans that have not been exported yet.
+pub fn shutdown() -> Result<()> {
+    if let Some(provider) = PROVIDER.get() {
+        provider.shutdown()?;
+    }
+    Ok(())
+}
+
+/// Makes sure every request has an `x-request-id`, generating one when the caller
+/// did not send it, and echoes it back in the response. Add it after
+/// [`trace_http`] so the request span can pick it up.
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let request_id = match request.headers().get(REQUEST_ID_HEADER) {
+        Some(request_id) => request_id.clone(),
+        None => {
+            let request_id = HeaderValue::from_str(&Uuid::new_v4().to_string())
+                .expect("A UUID is a valid header value");
+            request
+                .headers_mut()
+                .insert(REQUEST_ID_HEADER, request_id.clone());
+            request_id
+        }
+    };
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    response
+}
+
+/// Runs each request in a span that continues the caller's trace, if any.
+/// Add it with `Router::layer(axum::middleware::from_fn(telemetry::trace_http))`.
+pub async fn trace_http(request: Request, next: Next) -> Response {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".into());
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|request_id| request_id.to_str().ok())
+        .unwrap_or_default();
+
+    let span = info_span!(
+        "http_request",
+        otel.name = format!("{} {}", request.method(), route),
+        otel.kind = "server",
+        http.method = %request.method(),
+        http.route = route,
+        request_id,
+    );
+    span.set_parent(parent);
+
+    next.run(request).instrument(span).await
+}
+
+/// Serializes the context of the current span, to be stored with an outbox row.
+pub fn current_context() -> HashMap<String, String> {
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut carrier)
+    });
+    carrier
+}
+
+/// Parses a context stored by [`current_context`] or received in AMQP headers.
+pub fn context_from(carrier: &HashMap<String, String>) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(carrier))
+}
+
+/// Writes the context of `span` into AMQP message headers.
+pub fn inject_headers(span: &Span, headers: &mut FieldTable) {
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut HeaderInjector(headers))
+    });
+}
+
+/// Reads the trace context a publisher wrote into AMQP message headers.
+pub fn extract_headers(headers: &Option<FieldTable>) -> Context {
+    let carrier: HashMap<String, String> = headers
+        .iter()
+        .flat_map(|headers| headers.inner())
+        .filter_map(|(key, value)| match value {
+            AMQPValue::LongString(value) => Some((key.to_string(), value.to_string())),
+            _ => None,
+        })
+        .collect();
+
+    context_from(&carrier)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut FieldTable);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0
+            .insert(key.into(), AMQPValue::LongString(value.into()));
+    }
+}
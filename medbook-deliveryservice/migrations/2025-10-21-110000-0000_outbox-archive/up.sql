@@ -0,0 +1,24 @@
+-- Your SQL goes here
+
+ALTER TABLE "outbox"
+  ADD COLUMN "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  ADD COLUMN "processed_at" TIMESTAMPTZ;
+
+UPDATE "outbox" SET "processed_at" = NOW() WHERE "status" = 'PROCESSED';
+
+-- The relay only ever scans pending rows, so keep that scan independent of
+-- how many processed rows are waiting for the retention job.
+CREATE INDEX "outbox_pending_idx" ON "outbox" ("next_attempt_at", "id")
+  WHERE "status" = 'PENDING';
+
+CREATE TABLE "outbox_archive" (
+  "id" integer PRIMARY KEY,
+  "event_type" text NOT NULL,
+  "payload" text NOT NULL,
+  "status" text NOT NULL,
+  "attempts" integer NOT NULL,
+  "last_error" text,
+  "created_at" TIMESTAMPTZ NOT NULL,
+  "processed_at" TIMESTAMPTZ,
+  "archived_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
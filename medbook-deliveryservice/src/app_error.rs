@@ -1,38 +1,32 @@
-use std::error::Error;
-
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
+use medbook_common::app_error::{BaseError, error_response};
 use thiserror::Error;
-use tracing::error;
 
 #[derive(Error, Debug)]
 pub enum AppError {
-    #[error("Service \"{0}\" is unreachable")]
-    ServiceUnreachable(String),
-
     #[error("\"{0}\" is not a delivery state")]
     BadDeliveryState(String),
 
     #[error(transparent)]
-    Other(#[from] anyhow::Error),
+    Base(#[from] BaseError),
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        error!("Error: {}", self.to_string());
-        error!("Detailed error: {:#?}", self.source());
-
-        let (status, message) = match &self {
-            AppError::ServiceUnreachable(_) => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
-            AppError::BadDeliveryState(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-            AppError::Other(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal server error".into(),
-            ),
-        };
+        match self {
+            AppError::Base(err) => err.into_response(),
+            AppError::BadDeliveryState(_) => {
+                error_response(&self, StatusCode::BAD_REQUEST, self.to_string())
+            }
+        }
+    }
+}
 
-        (status, message).into_response()
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        AppError::Base(err.into())
     }
 }
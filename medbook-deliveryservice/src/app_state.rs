@@ -1,14 +1,19 @@
 use anyhow::Result;
 use reqwest::Client;
-use rmq_wrappers::Rmq;
 
-use crate::db::{self, DbPool};
+use medbook_common::{
+    app_state::BaseState,
+    db::{self, DbPool},
+    rmq::Rmq,
+    shutdown::Shutdown,
+};
 
 #[derive(Clone)]
 pub struct AppState {
     pub db_pool: DbPool,
     pub http_client: Client,
     pub rmq_client: Rmq,
+    pub shutdown: Shutdown,
 }
 
 impl AppState {
@@ -17,6 +22,21 @@ impl AppState {
             db_pool: db::connect(&std::env::var("DATABASE_URL")?).await?,
             http_client: Client::new(),
             rmq_client: Rmq::connect(&std::env::var("RMQ_URL")?).await?,
+            shutdown: Shutdown::new(),
         })
     }
 }
+
+impl BaseState for AppState {
+    fn db_pool(&self) -> &DbPool {
+        &self.db_pool
+    }
+
+    fn rmq_client(&self) -> &Rmq {
+        &self.rmq_client
+    }
+
+    fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+}
@@ -1,10 +1,10 @@
 use anyhow::{Context, Result};
 use diesel::SelectableHelper;
-use diesel_async::RunQueryDsl;
+use diesel_async::{AsyncConnection, RunQueryDsl};
 use futures::future::BoxFuture;
-use lapin::{message::Delivery, options::BasicAckOptions};
-use medbook_events::DeliveryOrderSuccessEvent;
-use tracing::info;
+use medbook_common::inbox;
+use medbook_events::{DeliveryOrderSuccessEvent, EventEnvelope};
+use tracing::{Instrument, info, info_span};
 
 use crate::{
     app_state::AppState,
@@ -12,31 +12,46 @@ use crate::{
     schema::delivery,
 };
 
-pub fn order_success(delivery: Delivery, state: AppState) -> BoxFuture<'static, Result<()>> {
-    Box::pin(async move {
-        let payload: DeliveryOrderSuccessEvent = serde_json::from_str(str::from_utf8(&delivery.data)?)?;
-        info!("Received event: {:?}", payload);
+pub fn order_success(
+    event: EventEnvelope<DeliveryOrderSuccessEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!("order_success", order_id = event.payload.order_id);
+    Box::pin(
+        async move {
+            let conn = &mut state
+                .db_pool
+                .get()
+                .await
+                .context("Failed to obtain a DB connection pool")?;
 
-        let conn = &mut state
-            .db_pool
-            .get()
-            .await
-            .context("Failed to obtain a DB connection pool")?;
+            conn.transaction(|tx| {
+                let event = &event;
+                Box::pin(async move {
+                    if !inbox::record(tx, event).await? {
+                        return Ok(());
+                    }
 
-        let deliv = diesel::insert_into(delivery::table)
-            .values(CreateDeliveryEntity {
-                order_id: payload.order_id,
-                status: "PREPARING".into(),
-            })
-            .returning(DeliveryEntity::as_returning())
-            .get_result(conn)
-            .await
-            .context("Failed to create delivery")?;
+                    let payload = &event.payload;
 
-        info!("Delivery #{} has been created", deliv.id);
+                    let deliv = diesel::insert_into(delivery::table)
+                        .values(CreateDeliveryEntity {
+                            order_id: payload.order_id,
+                            status: "PREPARING".into(),
+                        })
+                        .returning(DeliveryEntity::as_returning())
+                        .get_result(tx)
+                        .await
+                        .context("Failed to create delivery")?;
 
-        delivery.ack(BasicAckOptions::default()).await?;
+                    info!(delivery_id = deliv.id, "Delivery has been created");
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .await?;
 
-        Ok(())
-    })
+            Ok(())
+        }
+        .instrument(span),
+    )
 }
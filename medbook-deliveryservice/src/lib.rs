@@ -1,8 +1,9 @@
 pub mod app_error;
 pub mod app_state;
 pub mod consumers;
-pub mod db;
 pub mod models;
-pub mod outbox;
 pub mod routes;
 pub mod schema;
+
+/// Name this service reports its traces under.
+pub const SERVICE_NAME: &str = "medbook-deliveryservice";
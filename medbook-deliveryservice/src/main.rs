@@ -1,34 +1,50 @@
-use medbook_deliveryservice::{app_state, consumers, outbox, routes};
+use medbook_common::{
+    consumers::ConsumerConfig,
+    dead_letters, health,
+    outbox::{self, OutboxConfig},
+    prometheus, shutdown, telemetry,
+};
+use medbook_deliveryservice::{SERVICE_NAME, app_state, consumers, routes};
+use medbook_events::DeliveryOrderSuccessEvent;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
-        .init();
-
     dotenvy::dotenv().ok();
+    telemetry::init(SERVICE_NAME)?;
     tracing::info!(".env files loaded");
 
     let ip = format!("0.0.0.0:{}", std::env::var("PORT")?);
     tracing::info!("Starting DeliveryService on {}...", ip);
 
+    prometheus::init()?;
     let app_state = app_state::AppState::init().await?;
+    let consumer_config = ConsumerConfig::from_env()?;
 
-    consumers::init(
-        "delivery.order_success".into(),
+    medbook_common::consumers::consume::<DeliveryOrderSuccessEvent, _>(
         consumers::delivery::order_success,
         app_state.clone(),
-    );
+        consumer_config.clone(),
+    )?;
 
-    outbox::init(app_state.clone());
+    outbox::init(app_state.clone(), OutboxConfig::from_env()?);
 
     let app = axum::Router::new()
         .nest("/delivery", routes::delivery::routes())
+        .nest("/admin/dead-letters", dead_letters::routes()?)
+        .nest("/health", health::routes())
         .route("/health-check", axum::routing::get(|| async { "OK" }))
-        .with_state(app_state);
+        .nest("/metrics", prometheus::routes())
+        .layer(axum::middleware::from_fn(prometheus::track_http))
+        .layer(axum::middleware::from_fn(telemetry::trace_http))
+        .layer(axum::middleware::from_fn(telemetry::request_id))
+        .with_state(app_state.clone());
 
     let listener = tokio::net::TcpListener::bind(ip).await.unwrap();
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(app_state.shutdown.clone().signal())
+        .await?;
+
+    shutdown::drain(app_state).await?;
 
     Ok(())
 }
@@ -40,21 +40,3 @@ pub struct CreateDeliveryEntity {
     pub order_id: i32,
     pub status: String,
 }
-
-#[derive(Queryable, Selectable, Debug)]
-#[diesel(table_name = crate::schema::outbox)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct OutboxEntity {
-    pub id: i32,
-    pub event_type: String,
-    pub payload: String,
-    pub status: String,
-}
-
-#[derive(Queryable, Insertable, Selectable, Debug)]
-#[diesel(table_name = crate::schema::outbox)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct CreateOutboxEntity {
-    pub event_type: String,
-    pub payload: String,
-}
@@ -8,13 +8,9 @@ use axum::{
 use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
 use diesel_async::RunQueryDsl;
 use serde::Deserialize;
+use tracing::instrument;
 
-use crate::{
-    app_error::AppError,
-    app_state::AppState,
-    models::DeliveryEntity,
-    schema::{delivery, outbox},
-};
+use crate::{app_error::AppError, app_state::AppState, models::DeliveryEntity, schema::delivery};
 
 pub fn routes() -> Router<AppState> {
     Router::new()
@@ -52,6 +48,7 @@ struct UpdateDeliveryStatusReq {
     status: String,
 }
 
+#[instrument(skip_all, fields(delivery_id = id, status = body.status))]
 async fn update_delivery_status(
     Path(id): Path<i32>,
     State(state): State<AppState>,
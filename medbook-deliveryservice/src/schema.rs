@@ -34,13 +34,4 @@ diesel::table! {
     }
 }
 
-diesel::table! {
-    outbox (id) {
-        id -> Int4,
-        event_type -> Text,
-        payload -> Text,
-        status -> Text,
-    }
-}
-
-diesel::allow_tables_to_appear_in_same_query!(delivery, delivery_address, outbox,);
+diesel::allow_tables_to_appear_in_same_query!(delivery, delivery_address,);
@@ -1,7 +1,77 @@
-use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A payload that travels on its own queue.
+///
+/// Publishing and consuming go through `Event::TYPE`, so a payload can only
+/// ever be sent to (and parsed from) the queue it belongs to.
+pub trait Event: Serialize + DeserializeOwned + Debug + Send + Sync + 'static {
+    /// Queue the event is published to and consumed from.
+    const TYPE: &'static str;
+
+    /// Payload schema version, bumped on breaking changes. Consumers dead-letter
+    /// envelopes of any other version.
+    const VERSION: u32 = 1;
+
+    /// Aggregate the event belongs to. Consumers handle events with the same key
+    /// one at a time, in order; events without a key may run in any order.
+    fn ordering_key(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Metadata wrapped around every event payload that goes through the outbox.
+///
+/// `correlation_id` stays the same for every event of one order's saga, while
+/// `causation_id` points at the event that directly triggered this one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EventEnvelope<T> {
+    pub event_id: Uuid,
+    pub event_type: String,
+    pub schema_version: u32,
+    pub occurred_at: DateTime<Utc>,
+    pub correlation_id: Uuid,
+    pub causation_id: Option<Uuid>,
+    pub producer: String,
+    pub payload: T,
+}
+
+impl<T: Event> EventEnvelope<T> {
+    /// Starts a new saga: the correlation id is the event's own id.
+    pub fn new(producer: impl Into<String>, payload: T) -> Self {
+        let event_id = Uuid::new_v4();
+
+        Self {
+            event_id,
+            event_type: T::TYPE.into(),
+            schema_version: T::VERSION,
+            occurred_at: Utc::now(),
+            correlation_id: event_id,
+            causation_id: None,
+            producer: producer.into(),
+            payload,
+        }
+    }
+
+    /// Attaches the event to an existing saga.
+    pub fn with_correlation_id(mut self, correlation_id: Uuid) -> Self {
+        self.correlation_id = correlation_id;
+        self
+    }
+
+    /// Builds the envelope for an event emitted in reaction to this one.
+    pub fn follow_up<U: Event>(&self, producer: impl Into<String>, payload: U) -> EventEnvelope<U> {
+        let mut envelope =
+            EventEnvelope::new(producer, payload).with_correlation_id(self.correlation_id);
+        envelope.causation_id = Some(self.event_id);
+        envelope
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OrderItem {
     pub product_id: i32,
     pub quantity: i32,
@@ -13,22 +83,57 @@ pub struct OrderRequestedEvent {
     pub order_items: Vec<OrderItem>,
 }
 
+impl Event for OrderRequestedEvent {
+    const TYPE: &'static str = "inventory.order_requested";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OrderReservedEvent {
     pub order_id: i32,
 }
 
+impl Event for OrderReservedEvent {
+    const TYPE: &'static str = "orders.order_reserved";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OrderRejectedEvent {
     pub order_id: i32,
 }
 
+impl Event for OrderRejectedEvent {
+    const TYPE: &'static str = "orders.order_rejected";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OrderPayRequestEvent {
     pub payment_id: Uuid,
     pub order_id: i32,
     pub amount: f32,
     pub provider: String,
+    /// What is being paid for, so returned items can be checked against it.
+    #[serde(default)]
+    pub items: Vec<OrderItem>,
+}
+
+impl Event for OrderPayRequestEvent {
+    const TYPE: &'static str = "payments.pay_request";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -39,7 +144,158 @@ pub struct OrderPaymentSuccessEvent {
     pub provider: String,
 }
 
+impl Event for OrderPaymentSuccessEvent {
+    const TYPE: &'static str = "orders.payment_success";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeliveryOrderSuccessEvent {
     pub order_id: i32,
 }
+
+impl Event for DeliveryOrderSuccessEvent {
+    const TYPE: &'static str = "delivery.order_success";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
+/// The order was cancelled: the stock reserved for it goes back to the shelf.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InventoryReleaseRequestedEvent {
+    pub order_id: i32,
+}
+
+impl Event for InventoryReleaseRequestedEvent {
+    const TYPE: &'static str = "inventory.release_reservation";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
+/// The order was cancelled while its payment was still pending.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PaymentVoidRequestedEvent {
+    pub payment_id: Uuid,
+    pub order_id: i32,
+}
+
+impl Event for PaymentVoidRequestedEvent {
+    const TYPE: &'static str = "payments.void_request";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
+/// The order was paid for after it was cancelled or expired: the money goes back.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PaymentRefundRequestedEvent {
+    pub payment_id: Uuid,
+    pub order_id: i32,
+    pub reason: String,
+}
+
+impl Event for PaymentRefundRequestedEvent {
+    const TYPE: &'static str = "payments.refund_request";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
+/// The payment was declined or not completed in time.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PaymentFailedEvent {
+    pub payment_id: Uuid,
+    pub order_id: i32,
+    pub reason: String,
+}
+
+impl Event for PaymentFailedEvent {
+    const TYPE: &'static str = "orders.payment_failed";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
+/// Money was given back for a paid order, fully or in part.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PaymentRefundedEvent {
+    pub refund_id: Uuid,
+    pub payment_id: Uuid,
+    pub order_id: i32,
+    /// In satang, the minor unit of THB.
+    pub amount_minor: i64,
+    /// Whether the whole payment has now been refunded.
+    pub full: bool,
+    /// Items the patient returned; empty when the refund does not restock anything.
+    pub returned_items: Vec<OrderItem>,
+}
+
+impl Event for PaymentRefundedEvent {
+    const TYPE: &'static str = "orders.payment_refunded";
+    const VERSION: u32 = 2;
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
+/// Items of a paid order were returned and go back to the shelf.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InventoryRestockRequestedEvent {
+    pub order_id: i32,
+    pub items: Vec<OrderItem>,
+}
+
+impl Event for InventoryRestockRequestedEvent {
+    const TYPE: &'static str = "inventory.restock";
+
+    fn ordering_key(&self) -> Option<String> {
+        Some(self.order_id.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follow_up_stays_in_the_saga_of_its_cause() {
+        let cause = EventEnvelope::new(
+            "orders",
+            OrderRequestedEvent {
+                order_id: 1,
+                order_items: vec![],
+            },
+        );
+
+        let effect = cause.follow_up("inventory", OrderReservedEvent { order_id: 1 });
+
+        assert_eq!(effect.correlation_id, cause.correlation_id);
+        assert_eq!(effect.causation_id, Some(cause.event_id));
+        assert_ne!(effect.event_id, cause.event_id);
+        assert_eq!(effect.event_type, OrderReservedEvent::TYPE);
+        assert_eq!(effect.producer, "inventory");
+    }
+
+    #[test]
+    fn follow_up_keeps_an_inherited_correlation_id() {
+        let saga = Uuid::new_v4();
+        let cause = EventEnvelope::new("orders", OrderReservedEvent { order_id: 1 })
+            .with_correlation_id(saga);
+
+        let effect = cause.follow_up("payments", OrderRejectedEvent { order_id: 1 });
+
+        assert_eq!(effect.correlation_id, saga);
+        assert_eq!(effect.causation_id, Some(cause.event_id));
+    }
+}
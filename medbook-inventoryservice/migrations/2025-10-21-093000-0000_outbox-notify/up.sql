@@ -0,0 +1,15 @@
+-- Your SQL goes here
+
+-- Wakes the outbox relay (LISTEN outbox_inserted) as soon as a row is committed.
+-- NOTIFY is only delivered on commit, so the relay never sees an uncommitted row.
+CREATE FUNCTION outbox_notify() RETURNS trigger AS $$
+BEGIN
+  PERFORM pg_notify('outbox_inserted', '');
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER outbox_notify
+  AFTER INSERT ON "outbox"
+  FOR EACH STATEMENT
+  EXECUTE FUNCTION outbox_notify();
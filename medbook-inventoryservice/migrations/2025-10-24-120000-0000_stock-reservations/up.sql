@@ -0,0 +1,14 @@
+-- Your SQL goes here
+
+-- What each order holds of `inventory.reserved_quantity`, so it can be handed back
+CREATE TABLE "stock_reservations" (
+  "order_id" integer NOT NULL,
+  "product_id" integer NOT NULL REFERENCES "product" ("id") ON DELETE CASCADE,
+  "quantity" integer NOT NULL,
+  "status" text NOT NULL DEFAULT 'RESERVED', -- RESERVED, RELEASED
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  "updated_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY ("order_id", "product_id")
+);
+
+SELECT diesel_manage_updated_at('stock_reservations');
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE "outbox_archive" DROP COLUMN "trace_context";
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+-- Archived rows keep the trace they were published in
+ALTER TABLE "outbox_archive" ADD COLUMN "trace_context" text;
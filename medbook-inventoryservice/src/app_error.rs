@@ -2,8 +2,8 @@ use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
+use medbook_common::app_error::{BaseError, error_response};
 use thiserror::Error;
-use tracing::error;
 
 #[derive(Error, Debug)]
 pub enum AppError {
@@ -11,22 +11,22 @@ pub enum AppError {
     ProductNotFound(i32),
 
     #[error(transparent)]
-    Other(#[from] anyhow::Error),
+    Base(#[from] BaseError),
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        error!("Error: {}", self.to_string());
-        let (status, message) = match &self {
-            AppError::ProductNotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
-            // AppError::InventoryNotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
-            // AppError::DbError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Database error".into()),
-            AppError::Other(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal server error".into(),
-            ),
-        };
+        match self {
+            AppError::Base(err) => err.into_response(),
+            AppError::ProductNotFound(_) => {
+                error_response(&self, StatusCode::NOT_FOUND, self.to_string())
+            }
+        }
+    }
+}
 
-        (status, message).into_response()
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        AppError::Base(err.into())
     }
 }
@@ -1,97 +1,285 @@
+use std::collections::BTreeMap;
+
 use anyhow::Result;
-use diesel::{ExpressionMethods, QueryDsl};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
 use diesel_async::{AsyncConnection, RunQueryDsl};
 use futures::future::BoxFuture;
-use lapin::{message::Delivery, options::BasicAckOptions};
-use medbook_events::{OrderRequestedEvent, OrderReservedEvent};
+use medbook_common::{inbox, outbox};
+use medbook_events::{
+    EventEnvelope, InventoryReleaseRequestedEvent, InventoryRestockRequestedEvent,
+    OrderRejectedEvent, OrderRequestedEvent, OrderReservedEvent,
+};
+use thiserror::Error;
+use tracing::{Instrument, error, info, info_span, warn};
 
 use crate::{
+    SERVICE_NAME,
     app_state::AppState,
-    models::CreateOutboxEntity,
-    schema::{inventory, outbox},
+    models::CreateStockReservationEntity,
+    schema::{inventory, stock_reservations},
 };
 
-pub fn reserve_stock(delivery: Delivery, state: AppState) -> BoxFuture<'static, Result<()>> {
-    Box::pin(async move {
-        let conn = &mut state.db_pool.get().await?;
-        let payload: OrderRequestedEvent = serde_json::from_str(str::from_utf8(&delivery.data)?)?;
+/// The only reason an order is rejected.
+#[derive(Error, Debug)]
+#[error("Insufficient stock for product {0}")]
+struct InsufficientStock(i32);
 
-        // Step 1: Try to reserve stock in one atomic transaction
-        let result = conn
-            .transaction(move |conn| {
-                Box::pin(async move {
-                    for item in &payload.order_items {
-                        let affected_rows = diesel::update(
-                            inventory::table.filter(inventory::product_id.eq(item.product_id)),
-                        )
-                        .filter(
-                            (inventory::total_quantity
-                                - inventory::reserved_quantity
-                                - inventory::sold_quantity)
-                                .ge(item.quantity),
-                        )
-                        .set(
-                            inventory::reserved_quantity
-                                .eq(inventory::reserved_quantity + item.quantity),
-                        )
-                        .execute(conn)
-                        .await?;
+pub fn reserve_stock(
+    event: EventEnvelope<OrderRequestedEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!("reserve_stock", order_id = event.payload.order_id);
+    Box::pin(
+        async move {
+            let payload = &event.payload;
 
-                        if affected_rows == 0 {
-                            return Err(anyhow::anyhow!(
-                                "Insufficient stock for product {}",
-                                item.product_id
-                            ));
+            // Repeated products are reserved as one row, and rows are always locked
+            // in product order so concurrent reservations cannot deadlock
+            let mut items: BTreeMap<i32, i32> = BTreeMap::new();
+            for item in &payload.order_items {
+                *items.entry(item.product_id).or_default() += item.quantity;
+            }
+
+            let conn = &mut state.db_pool.get().await?;
+
+            // Step 1: Try to reserve stock in one atomic transaction
+            let result = conn
+                .transaction(|conn| {
+                    let event = &event;
+                    let items = &items;
+                    Box::pin(async move {
+                        if !inbox::record(conn, event).await? {
+                            return Ok(false);
                         }
-                    }
 
-                    // All items available → insert success outbox
-                    diesel::insert_into(outbox::table)
-                        .values(CreateOutboxEntity {
-                            event_type: "orders.order_reserved".into(),
-                            payload: serde_json::to_string(&OrderReservedEvent {
-                                order_id: payload.order_id,
-                            })?,
-                        })
-                        .execute(conn)
+                        for (&product_id, &quantity) in items {
+                            let affected_rows = diesel::update(
+                                inventory::table.filter(inventory::product_id.eq(product_id)),
+                            )
+                            .filter(
+                                (inventory::total_quantity
+                                    - inventory::reserved_quantity
+                                    - inventory::sold_quantity)
+                                    .ge(quantity),
+                            )
+                            .set(
+                                inventory::reserved_quantity
+                                    .eq(inventory::reserved_quantity + quantity),
+                            )
+                            .execute(conn)
+                            .await?;
+
+                            if affected_rows == 0 {
+                                metrics::counter!(
+                                    "stock_reservation_failures_total",
+                                    "product_id" => product_id.to_string(),
+                                )
+                                .increment(1);
+                                return Err(InsufficientStock(product_id).into());
+                            }
+                        }
+
+                        diesel::insert_into(stock_reservations::table)
+                            .values(
+                                items
+                                    .iter()
+                                    .map(|(&product_id, &quantity)| CreateStockReservationEntity {
+                                        order_id: payload.order_id,
+                                        product_id,
+                                        quantity,
+                                    })
+                                    .collect::<Vec<_>>(),
+                            )
+                            .execute(conn)
+                            .await?;
+
+                        // All items available → insert success outbox
+                        outbox::publish(
+                            conn,
+                            &event.follow_up(
+                                SERVICE_NAME,
+                                OrderReservedEvent {
+                                    order_id: payload.order_id,
+                                },
+                            ),
+                        )
                         .await?;
 
-                    Ok::<_, anyhow::Error>(())
+                        Ok::<_, anyhow::Error>(true)
+                    })
                 })
-            })
-            .await;
+                .await;
 
-        // Step 2: Handle transaction outcome
-        match result {
-            Ok(_) => {
-                tracing::info!("Reservation for order #{} successful", payload.order_id);
-                delivery.ack(BasicAckOptions::default()).await?;
-            }
+            // Step 2: Handle transaction outcome
+            match result {
+                Ok(false) => {}
+
+                Ok(true) => {
+                    info!("Reservation successful");
+                }
+
+                // Anything else, e.g. a lost connection or a deadlock, is retried
+                Err(e) if !e.is::<InsufficientStock>() => return Err(e),
 
-            Err(e) => {
-                tracing::error!("Reservation failed: {:?}", e);
-
-                // Independent transaction for "order_rejected" outbox
-                let conn = &mut state.db_pool.get().await?;
-                diesel::insert_into(outbox::table)
-                    .values(CreateOutboxEntity {
-                        event_type: "orders.order_rejected".into(),
-                        payload: serde_json::to_string(&OrderReservedEvent {
-                            order_id: payload.order_id,
-                        })?,
+                Err(e) => {
+                    error!(error = format!("{:#}", e), "Reservation failed");
+
+                    // Independent transaction for "order_rejected" outbox
+                    let conn = &mut state.db_pool.get().await?;
+                    conn.transaction(|conn| {
+                        let event = &event;
+                        Box::pin(async move {
+                            if !inbox::record(conn, event).await? {
+                                return Ok(());
+                            }
+
+                            outbox::publish(
+                                conn,
+                                &event.follow_up(
+                                    SERVICE_NAME,
+                                    OrderRejectedEvent {
+                                        order_id: payload.order_id,
+                                    },
+                                ),
+                            )
+                            .await?;
+
+                            Ok::<_, anyhow::Error>(())
+                        })
                     })
-                    .execute(conn)
                     .await?;
 
-                tracing::info!("Order #{} rejected, outbox event created", payload.order_id);
-
-                // Acknowledge message (no retry)
-                delivery.ack(BasicAckOptions::default()).await?;
+                    // The message is still acknowledged: a rejection is a final answer, not a retry
+                    info!("Order rejected, outbox event created");
+                }
             }
+
+            Ok(())
+        }
+        .instrument(span),
+    )
+}
+
+/// Hands back the stock reserved for a cancelled order.
+pub fn release_reservation(
+    event: EventEnvelope<InventoryReleaseRequestedEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!("release_reservation", order_id = event.payload.order_id);
+    Box::pin(
+        async move {
+            let conn = &mut state.db_pool.get().await?;
+
+            conn.transaction(|conn| {
+                let event = &event;
+                Box::pin(async move {
+                    if !inbox::record(conn, event).await? {
+                        return Ok(());
+                    }
+
+                    let released: Vec<(i32, i32)> = diesel::update(
+                        stock_reservations::table
+                            .filter(stock_reservations::order_id.eq(event.payload.order_id))
+                            .filter(stock_reservations::status.eq("RESERVED")),
+                    )
+                    .set(stock_reservations::status.eq("RELEASED"))
+                    .returning((stock_reservations::product_id, stock_reservations::quantity))
+                    .get_results(conn)
+                    .await?;
+
+                    for (product_id, quantity) in &released {
+                        diesel::update(inventory::table.find(product_id))
+                            .set(
+                                inventory::reserved_quantity
+                                    .eq(inventory::reserved_quantity - quantity),
+                            )
+                            .execute(conn)
+                            .await?;
+                    }
+
+                    info!(products = released.len(), "Reservation released");
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .await?;
+
+            Ok(())
         }
+        .instrument(span),
+    )
+}
+
+/// Hands back the stock of items returned from a partially refunded order.
+pub fn restock(
+    event: EventEnvelope<InventoryRestockRequestedEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!("restock", order_id = event.payload.order_id);
+    Box::pin(
+        async move {
+            let conn = &mut state.db_pool.get().await?;
+
+            conn.transaction(|conn| {
+                let event = &event;
+                Box::pin(async move {
+                    if !inbox::record(conn, event).await? {
+                        return Ok(());
+                    }
+
+                    let payload = &event.payload;
+
+                    for item in &payload.items {
+                        let remaining: Option<i32> = diesel::update(
+                            stock_reservations::table
+                                .find((payload.order_id, item.product_id))
+                                .filter(stock_reservations::status.eq("RESERVED"))
+                                .filter(stock_reservations::quantity.ge(item.quantity)),
+                        )
+                        .set(
+                            stock_reservations::quantity
+                                .eq(stock_reservations::quantity - item.quantity),
+                        )
+                        .returning(stock_reservations::quantity)
+                        .get_result(conn)
+                        .await
+                        .optional()?;
+
+                        let Some(remaining) = remaining else {
+                            // Never reserved, already released, or more than was ordered
+                            warn!(
+                                product_id = item.product_id,
+                                quantity = item.quantity,
+                                "No reservation to restock the item from, skipping it"
+                            );
+                            continue;
+                        };
 
-        delivery.ack(BasicAckOptions::default()).await?;
+                        if remaining == 0 {
+                            diesel::update(
+                                stock_reservations::table.find((payload.order_id, item.product_id)),
+                            )
+                            .set(stock_reservations::status.eq("RELEASED"))
+                            .execute(conn)
+                            .await?;
+                        }
+
+                        diesel::update(inventory::table.find(item.product_id))
+                            .set(
+                                inventory::reserved_quantity
+                                    .eq(inventory::reserved_quantity - item.quantity),
+                            )
+                            .execute(conn)
+                            .await?;
+                    }
+
+                    info!(products = payload.items.len(), "Returned items restocked");
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .await?;
 
-        Ok(())
-    })
+            Ok(())
+        }
+        .instrument(span),
+    )
 }
@@ -1,9 +1,10 @@
 pub mod app_error;
 pub mod app_state;
 pub mod consumers;
-pub mod db;
 pub mod models;
-pub mod outbox;
 pub mod routes;
 pub mod schema;
 pub mod schema_custom;
+
+/// Name stamped as `producer` on every event this service emits.
+pub const SERVICE_NAME: &str = "medbook-inventoryservice";
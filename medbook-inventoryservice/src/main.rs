@@ -1,39 +1,68 @@
 use anyhow::Result;
 use axum::Router;
-use medbook_inventoryservice::{app_state::AppState, consumers, outbox, routes};
+use medbook_common::{
+    consumers::ConsumerConfig,
+    dead_letters, health,
+    outbox::{self, OutboxConfig},
+    prometheus, shutdown, telemetry,
+};
+use medbook_events::{
+    InventoryReleaseRequestedEvent, InventoryRestockRequestedEvent, OrderRequestedEvent,
+};
+use medbook_inventoryservice::{SERVICE_NAME, app_state::AppState, consumers, routes};
 use tracing::info;
-use tracing_subscriber::filter::LevelFilter;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(LevelFilter::INFO)
-        .init();
-
     dotenvy::dotenv().ok();
+    telemetry::init(SERVICE_NAME)?;
     info!(".env files loaded");
 
     let ip = format!("0.0.0.0:{}", std::env::var("PORT")?);
     info!("Starting InventoryService on {}...", ip);
 
+    prometheus::init()?;
     let app_state = AppState::init().await?;
+    let consumer_config = ConsumerConfig::from_env()?;
 
-    consumers::init(
-        "inventory.order_requested".into(),
+    medbook_common::consumers::consume::<OrderRequestedEvent, _>(
         consumers::inventory::reserve_stock,
         app_state.clone(),
-    );
+        consumer_config.clone(),
+    )?;
+
+    medbook_common::consumers::consume::<InventoryReleaseRequestedEvent, _>(
+        consumers::inventory::release_reservation,
+        app_state.clone(),
+        consumer_config.clone(),
+    )?;
 
-    outbox::init(app_state.clone());
+    medbook_common::consumers::consume::<InventoryRestockRequestedEvent, _>(
+        consumers::inventory::restock,
+        app_state.clone(),
+        consumer_config.clone(),
+    )?;
+
+    outbox::init(app_state.clone(), OutboxConfig::from_env()?);
 
     let app = Router::new()
         .nest("/products", routes::products::routes())
         .nest("/inventory", routes::inventory::routes())
+        .nest("/admin/dead-letters", dead_letters::routes()?)
+        .nest("/health", health::routes())
         .route("/health-check", axum::routing::get(|| async { "OK" }))
-        .with_state(app_state);
+        .nest("/metrics", prometheus::routes())
+        .layer(axum::middleware::from_fn(prometheus::track_http))
+        .layer(axum::middleware::from_fn(telemetry::trace_http))
+        .layer(axum::middleware::from_fn(telemetry::request_id))
+        .with_state(app_state.clone());
 
     let listener = tokio::net::TcpListener::bind(ip).await.unwrap();
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(app_state.shutdown.clone().signal())
+        .await?;
+
+    shutdown::drain(app_state).await?;
 
     Ok(())
 }
@@ -24,22 +24,13 @@ pub struct InventoryEntity {
     pub sold_quantity: i32,
 }
 
-#[derive(Queryable, Selectable, Debug)]
-#[diesel(table_name = crate::schema::outbox)]
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::stock_reservations)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct OutboxEntity {
-    pub id: i32,
-    pub event_type: String,
-    pub payload: String,
-    pub status: String,
-}
-
-#[derive(Queryable, Insertable, Selectable, Debug)]
-#[diesel(table_name = crate::schema::outbox)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct CreateOutboxEntity {
-    pub event_type: String,
-    pub payload: String,
+pub struct CreateStockReservationEntity {
+    pub order_id: i32,
+    pub product_id: i32,
+    pub quantity: i32,
 }
 
 #[derive(Queryable, Serialize, QueryableByName)]
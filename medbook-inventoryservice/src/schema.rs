@@ -9,15 +9,6 @@ diesel::table! {
     }
 }
 
-diesel::table! {
-    outbox (id) {
-        id -> Int4,
-        event_type -> Text,
-        payload -> Text,
-        status -> Text,
-    }
-}
-
 diesel::table! {
     product (id) {
         id -> Int4,
@@ -27,6 +18,18 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    stock_reservations (order_id, product_id) {
+        order_id -> Int4,
+        product_id -> Int4,
+        quantity -> Int4,
+        status -> Text,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
 diesel::joinable!(inventory -> product (product_id));
+diesel::joinable!(stock_reservations -> product (product_id));
 
-diesel::allow_tables_to_appear_in_same_query!(inventory, outbox, product,);
+diesel::allow_tables_to_appear_in_same_query!(inventory, product, stock_reservations,);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE "orders" DROP CONSTRAINT "orders_status_check";
+
+ALTER TABLE "orders" ALTER COLUMN "status" SET DEFAULT 'Pending';
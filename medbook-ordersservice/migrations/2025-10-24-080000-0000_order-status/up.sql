@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+UPDATE "orders" SET "status" = 'PENDING' WHERE "status" = 'Pending';
+
+ALTER TABLE "orders" ALTER COLUMN "status" SET DEFAULT 'PENDING';
+
+-- Keep in sync with `OrderStatus`
+ALTER TABLE "orders" ADD CONSTRAINT "orders_status_check" CHECK (
+  "status" IN ('PENDING', 'RESERVED', 'REJECTED', 'PAYMENT_PROCESSING', 'PAYMENT_SUCCESS')
+);
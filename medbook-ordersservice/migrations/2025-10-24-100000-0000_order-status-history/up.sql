@@ -0,0 +1,14 @@
+-- Your SQL goes here
+
+CREATE TABLE "order_status_history" (
+  "id" bigserial PRIMARY KEY,
+  "order_id" integer NOT NULL REFERENCES "orders" ("id") ON DELETE CASCADE,
+  "from_status" text, -- NULL when the order was created
+  "to_status" text NOT NULL,
+  "actor_type" text NOT NULL, -- EVENT, PATIENT, SYSTEM
+  "actor" text NOT NULL, -- event type, patient id or job name
+  "event_id" uuid,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX "order_status_history_order_id_idx" ON "order_status_history" ("order_id", "created_at");
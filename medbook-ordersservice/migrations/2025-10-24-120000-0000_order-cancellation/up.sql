@@ -0,0 +1,8 @@
+-- Your SQL goes here
+
+ALTER TABLE "orders" DROP CONSTRAINT "orders_status_check";
+
+-- Keep in sync with `OrderStatus`
+ALTER TABLE "orders" ADD CONSTRAINT "orders_status_check" CHECK (
+  "status" IN ('PENDING', 'RESERVED', 'REJECTED', 'PAYMENT_PROCESSING', 'PAYMENT_SUCCESS', 'CANCELLED')
+);
@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX "orders_status_updated_at_idx";
+
+ALTER TABLE "orders" DROP CONSTRAINT "orders_status_check";
+
+ALTER TABLE "orders" ADD CONSTRAINT "orders_status_check" CHECK (
+  "status" IN ('PENDING', 'RESERVED', 'REJECTED', 'PAYMENT_PROCESSING', 'PAYMENT_SUCCESS', 'CANCELLED')
+);
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+ALTER TABLE "orders" DROP CONSTRAINT "orders_status_check";
+
+-- Keep in sync with `OrderStatus`
+ALTER TABLE "orders" ADD CONSTRAINT "orders_status_check" CHECK (
+  "status" IN ('PENDING', 'RESERVED', 'REJECTED', 'PAYMENT_PROCESSING', 'PAYMENT_SUCCESS', 'CANCELLED', 'EXPIRED')
+);
+
+-- For the expiry job
+CREATE INDEX "orders_status_updated_at_idx" ON "orders" ("status", "updated_at");
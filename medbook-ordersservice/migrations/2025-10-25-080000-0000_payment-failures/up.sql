@@ -0,0 +1,12 @@
+-- Your SQL goes here
+
+ALTER TABLE "orders" ADD COLUMN "payment_attempts" integer NOT NULL DEFAULT 0;
+
+UPDATE "orders" SET "payment_attempts" = 1 WHERE "payment_id" IS NOT NULL;
+
+ALTER TABLE "orders" DROP CONSTRAINT "orders_status_check";
+
+-- Keep in sync with `OrderStatus`
+ALTER TABLE "orders" ADD CONSTRAINT "orders_status_check" CHECK (
+  "status" IN ('PENDING', 'RESERVED', 'REJECTED', 'PAYMENT_PROCESSING', 'PAYMENT_SUCCESS', 'PAYMENT_FAILED', 'CANCELLED', 'EXPIRED')
+);
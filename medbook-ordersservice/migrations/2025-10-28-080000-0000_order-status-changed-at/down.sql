@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX "orders_status_status_changed_at_idx";
+CREATE INDEX "orders_status_updated_at_idx" ON "orders" ("status", "updated_at");
+
+ALTER TABLE "orders" DROP COLUMN "status_changed_at";
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+
+-- Set on every status change, unlike "updated_at" which any update bumps
+ALTER TABLE "orders" ADD COLUMN "status_changed_at" TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+UPDATE "orders" SET "status_changed_at" = COALESCE(
+  (
+    SELECT MAX("created_at") FROM "order_status_history"
+    WHERE "order_status_history"."order_id" = "orders"."id"
+  ),
+  "updated_at"
+);
+
+-- For the expiry job
+DROP INDEX "orders_status_updated_at_idx";
+CREATE INDEX "orders_status_status_changed_at_idx" ON "orders" ("status", "status_changed_at");
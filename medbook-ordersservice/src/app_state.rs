@@ -1,14 +1,24 @@
 use anyhow::Result;
 use reqwest::Client;
-use rmq_wrappers::Rmq;
 
-use crate::db::{self, DbPool};
+use medbook_common::{
+    app_state::BaseState,
+    config::env_or,
+    db::{self, DbPool},
+    rmq::Rmq,
+    shutdown::Shutdown,
+};
 
 #[derive(Clone)]
 pub struct AppState {
     pub db_pool: DbPool,
     pub http_client: Client,
     pub rmq_client: Rmq,
+    pub shutdown: Shutdown,
+    /// Base URL of the payment service, e.g. `http://localhost:3002`.
+    pub payment_service_url: String,
+    /// How many times an order may be paid for before a failed payment closes it.
+    pub max_payment_attempts: i32,
 }
 
 impl AppState {
@@ -17,6 +27,23 @@ impl AppState {
             db_pool: db::connect(&std::env::var("DATABASE_URL")?).await?,
             http_client: Client::new(),
             rmq_client: Rmq::connect(&std::env::var("RMQ_URL")?).await?,
+            shutdown: Shutdown::new(),
+            payment_service_url: std::env::var("PAYMENT_SERVICE_URL")?,
+            max_payment_attempts: env_or("ORDER_MAX_PAYMENT_ATTEMPTS", 3)?,
         })
     }
 }
+
+impl BaseState for AppState {
+    fn db_pool(&self) -> &DbPool {
+        &self.db_pool
+    }
+
+    fn rmq_client(&self) -> &Rmq {
+        &self.rmq_client
+    }
+
+    fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+}
@@ -0,0 +1,41 @@
+use diesel_async::AsyncPgConnection;
+use medbook_common::outbox;
+use medbook_events::{EventEnvelope, InventoryReleaseRequestedEvent, PaymentVoidRequestedEvent};
+
+use crate::{SERVICE_NAME, models::OrderEntity, order_status::OrderStatus};
+
+/// Publishes the events that undo what the other services did for `order`, as it
+/// was before it was cancelled or expired: its reserved stock is released and its
+/// pending payment voided.
+///
+/// A PENDING order has nothing reserved yet; if the reservation is still in
+/// flight, the `order_reserved` consumer releases it when it lands.
+pub async fn undo(conn: &mut AsyncPgConnection, order: &OrderEntity) -> anyhow::Result<()> {
+    if matches!(
+        order.status,
+        OrderStatus::Reserved | OrderStatus::PaymentProcessing
+    ) {
+        let envelope = EventEnvelope::new(
+            SERVICE_NAME,
+            InventoryReleaseRequestedEvent { order_id: order.id },
+        )
+        .with_correlation_id(order.correlation_id);
+
+        outbox::publish(conn, &envelope).await?;
+    }
+
+    if let (OrderStatus::PaymentProcessing, Some(payment_id)) = (order.status, order.payment_id) {
+        let envelope = EventEnvelope::new(
+            SERVICE_NAME,
+            PaymentVoidRequestedEvent {
+                payment_id,
+                order_id: order.id,
+            },
+        )
+        .with_correlation_id(order.correlation_id);
+
+        outbox::publish(conn, &envelope).await?;
+    }
+
+    Ok(())
+}
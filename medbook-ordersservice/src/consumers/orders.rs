@@ -1,100 +1,409 @@
-use anyhow::{Context, Result};
-use diesel::{ExpressionMethods, SelectableHelper};
+use anyhow::{Result, anyhow};
+use diesel::{OptionalExtension, QueryDsl};
 use diesel_async::{AsyncConnection, RunQueryDsl};
 use futures::future::BoxFuture;
-use lapin::{message::Delivery, options::BasicAckOptions};
+use medbook_common::{consumers::Poison, inbox, outbox};
 use medbook_events::{
-    DeliveryOrderSuccessEvent, OrderPaymentSuccessEvent, OrderRejectedEvent, OrderReservedEvent,
+    DeliveryOrderSuccessEvent, EventEnvelope, InventoryReleaseRequestedEvent,
+    InventoryRestockRequestedEvent, OrderPaymentSuccessEvent, OrderRejectedEvent,
+    OrderReservedEvent, PaymentFailedEvent, PaymentRefundRequestedEvent, PaymentRefundedEvent,
 };
-use tracing::info;
+use tracing::{Instrument, info, info_span};
 
 use crate::{
+    SERVICE_NAME,
     app_state::AppState,
-    models::{CreateOutboxEntity, OutboxEntity},
-    schema::{orders, outbox},
+    models::OrderEntity,
+    order_history::Actor,
+    order_status::{self, OrderStatus, TransitionError},
+    schema::orders,
 };
 
-pub fn order_reserved(delivery: Delivery, state: AppState) -> BoxFuture<'static, Result<()>> {
-    Box::pin(async move {
-        let conn = &mut state.db_pool.get().await?;
-        let payload: OrderReservedEvent = serde_json::from_str(str::from_utf8(&delivery.data)?)?;
-        info!("Received event: {:?}", payload);
+pub fn order_reserved(
+    event: EventEnvelope<OrderReservedEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!("order_reserved", order_id = event.payload.order_id);
+    Box::pin(
+        async move {
+            let conn = &mut state.db_pool.get().await?;
 
-        diesel::update(orders::table)
-            .filter(orders::id.eq(payload.order_id))
-            .set(orders::status.eq("RESERVED"))
-            .execute(conn)
-            .await?;
+            conn.transaction(|tx| {
+                let event = &event;
+                Box::pin(async move {
+                    if !inbox::record(tx, event).await? {
+                        return Ok(());
+                    }
 
-        info!("Order #{} has been reserved", payload.order_id);
+                    let payload = &event.payload;
 
-        delivery.ack(BasicAckOptions::default()).await?;
+                    match order_status::transition(
+                        tx,
+                        payload.order_id,
+                        OrderStatus::Reserved,
+                        &Actor::event(event),
+                    )
+                    .await
+                    {
+                        // Cancelled while the inventory was reserving it: hand the stock back
+                        Err(TransitionError::Illegal {
+                            from: OrderStatus::Cancelled,
+                            ..
+                        }) => {
+                            outbox::publish(
+                                tx,
+                                &event.follow_up(
+                                    SERVICE_NAME,
+                                    InventoryReleaseRequestedEvent {
+                                        order_id: payload.order_id,
+                                    },
+                                ),
+                            )
+                            .await?;
 
-        Ok(())
-    })
+                            info!(
+                                "Order was cancelled before it was reserved, releasing its stock"
+                            );
+                        }
+                        result => {
+                            result.map_err(TransitionError::into_consumer_error)?;
+                            info!("Order has been reserved");
+                        }
+                    }
+
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .await?;
+
+            Ok(())
+        }
+        .instrument(span),
+    )
 }
 
-pub fn order_rejected(delivery: Delivery, state: AppState) -> BoxFuture<'static, Result<()>> {
-    Box::pin(async move {
-        let conn = &mut state.db_pool.get().await?;
-        let payload: OrderRejectedEvent = serde_json::from_str(str::from_utf8(&delivery.data)?)?;
-        info!("Received event: {:?}", payload);
+pub fn order_rejected(
+    event: EventEnvelope<OrderRejectedEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!("order_rejected", order_id = event.payload.order_id);
+    Box::pin(
+        async move {
+            let conn = &mut state.db_pool.get().await?;
+
+            conn.transaction(|tx| {
+                let event = &event;
+                Box::pin(async move {
+                    if !inbox::record(tx, event).await? {
+                        return Ok(());
+                    }
 
-        diesel::update(orders::table)
-            .filter(orders::id.eq(payload.order_id))
-            .set(orders::status.eq("REJECTED"))
-            .execute(conn)
-            .await?;
+                    let payload = &event.payload;
 
-        info!("Order #{} has been rejected", payload.order_id);
+                    match order_status::transition(
+                        tx,
+                        payload.order_id,
+                        OrderStatus::Rejected,
+                        &Actor::event(event),
+                    )
+                    .await
+                    {
+                        // Nothing was reserved, so there is nothing to compensate
+                        Err(TransitionError::Illegal {
+                            from: OrderStatus::Cancelled,
+                            ..
+                        }) => info!("Order was cancelled before it was rejected"),
+                        result => {
+                            result.map_err(TransitionError::into_consumer_error)?;
+                            metrics::counter!("orders_rejected_total").increment(1);
+                            info!("Order has been rejected");
+                        }
+                    }
 
-        delivery.ack(BasicAckOptions::default()).await?;
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .await?;
 
-        Ok(())
-    })
+            Ok(())
+        }
+        .instrument(span),
+    )
 }
 
 pub fn order_payment_success(
-    delivery: Delivery,
+    event: EventEnvelope<OrderPaymentSuccessEvent>,
     state: AppState,
 ) -> BoxFuture<'static, Result<()>> {
-    Box::pin(async move {
-        let conn = &mut state.db_pool.get().await?;
-        let payload: OrderPaymentSuccessEvent =
-            serde_json::from_str(str::from_utf8(&delivery.data)?)?;
-
-        info!("Received event: {:?}", payload);
-
-        conn.transaction(move |tx| {
-            Box::pin(async move {
-                diesel::update(orders::table)
-                    .filter(orders::id.eq(payload.order_id))
-                    .set(orders::status.eq("PAYMENT_SUCCESS"))
-                    .execute(tx)
-                    .await?;
+    let span = info_span!("order_payment_success", order_id = event.payload.order_id);
+    Box::pin(
+        async move {
+            let conn = &mut state.db_pool.get().await?;
+
+            conn.transaction(|tx| {
+                let event = &event;
+                Box::pin(async move {
+                    if !inbox::record(tx, event).await? {
+                        return Ok(());
+                    }
 
-                let outbox = diesel::insert_into(outbox::table)
-                    .values(CreateOutboxEntity {
-                        event_type: "delivery.order_success".into(),
-                        payload: serde_json::to_string(&DeliveryOrderSuccessEvent {
-                            order_id: payload.order_id,
-                        })
-                        .context("Failed to serialize DeliveryOrderSuccessEvent")?,
-                    })
-                    .returning(OutboxEntity::as_returning())
-                    .get_result(tx)
+                    let payload = &event.payload;
+
+                    match order_status::transition(
+                        tx,
+                        payload.order_id,
+                        OrderStatus::PaymentSuccess,
+                        &Actor::event(event),
+                    )
                     .await
-                    .context("Outbox creation failed")?;
-                info!("Outbox created: {:?}", outbox);
+                    {
+                        // Paid while it was being cancelled or expiring: give the money back
+                        Err(TransitionError::Illegal {
+                            from: from @ (OrderStatus::Cancelled | OrderStatus::Expired),
+                            ..
+                        }) => {
+                            outbox::publish(
+                                tx,
+                                &event.follow_up(
+                                    SERVICE_NAME,
+                                    PaymentRefundRequestedEvent {
+                                        payment_id: payload.payment_id,
+                                        order_id: payload.order_id,
+                                        reason: format!("Order was paid for after it was {}", from),
+                                    },
+                                ),
+                            )
+                            .await?;
+
+                            metrics::counter!("orders_paid_after_close_total").increment(1);
+                            info!(%from, "Order was paid for after it was closed, refunding it");
+                            return Ok(());
+                        }
+                        result => {
+                            result.map_err(TransitionError::into_consumer_error)?;
+                        }
+                    }
 
-                info!("Order #{} has been successfully paid for", payload.order_id);
-                Ok::<_, anyhow::Error>(())
+                    outbox::publish(
+                        tx,
+                        &event.follow_up(
+                            SERVICE_NAME,
+                            DeliveryOrderSuccessEvent {
+                                order_id: payload.order_id,
+                            },
+                        ),
+                    )
+                    .await?;
+
+                    metrics::counter!("orders_paid_total").increment(1);
+                    info!("Order has been successfully paid for");
+                    Ok::<_, anyhow::Error>(())
+                })
             })
-        })
-        .await?;
+            .await?;
+
+            Ok(())
+        }
+        .instrument(span),
+    )
+}
 
-        delivery.ack(BasicAckOptions::default()).await?;
+pub fn order_payment_failed(
+    event: EventEnvelope<PaymentFailedEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!(
+        "order_payment_failed",
+        order_id = event.payload.order_id,
+        payment_id = %event.payload.payment_id,
+    );
+    Box::pin(
+        async move {
+            let max_attempts = state.max_payment_attempts;
+            let conn = &mut state.db_pool.get().await?;
+
+            conn.transaction(|tx| {
+                let event = &event;
+                Box::pin(async move {
+                    if !inbox::record(tx, event).await? {
+                        return Ok(());
+                    }
+
+                    let payload = &event.payload;
+
+                    let order: OrderEntity = orders::table
+                        .find(payload.order_id)
+                        .for_update()
+                        .get_result(tx)
+                        .await
+                        .optional()?
+                        .ok_or_else(|| {
+                            Poison(anyhow!("Order #{} does not exist", payload.order_id))
+                        })?;
+
+                    // A failure of an earlier attempt, or the order was cancelled or expired
+                    if order.status != OrderStatus::PaymentProcessing
+                        || order.payment_id != Some(payload.payment_id)
+                    {
+                        info!(
+                            status = %order.status,
+                            "Order no longer waits for this payment, ignoring its failure"
+                        );
+                        return Ok(());
+                    }
+
+                    if order.payment_attempts < max_attempts {
+                        order_status::transition(
+                            tx,
+                            order.id,
+                            OrderStatus::Reserved,
+                            &Actor::event(event),
+                        )
+                        .await
+                        .map_err(TransitionError::into_consumer_error)?;
+
+                        info!(
+                            attempts = order.payment_attempts,
+                            reason = payload.reason,
+                            "Payment failed, the order can be paid again"
+                        );
+                    } else {
+                        order_status::transition(
+                            tx,
+                            order.id,
+                            OrderStatus::PaymentFailed,
+                            &Actor::event(event),
+                        )
+                        .await
+                        .map_err(TransitionError::into_consumer_error)?;
+
+                        outbox::publish(
+                            tx,
+                            &event.follow_up(
+                                SERVICE_NAME,
+                                InventoryReleaseRequestedEvent { order_id: order.id },
+                            ),
+                        )
+                        .await?;
+
+                        metrics::counter!("orders_payment_failed_total").increment(1);
+                        info!(
+                            attempts = order.payment_attempts,
+                            reason = payload.reason,
+                            "Payment failed for the last time, releasing the order's stock"
+                        );
+                    }
+
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .await?;
+
+            Ok(())
+        }
+        .instrument(span),
+    )
+}
+
+/// A full refund closes the order and hands all of its stock back; a partial one
+/// only restocks the items the patient returned, if any.
+pub fn order_payment_refunded(
+    event: EventEnvelope<PaymentRefundedEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!(
+        "order_payment_refunded",
+        order_id = event.payload.order_id,
+        payment_id = %event.payload.payment_id,
+    );
+    Box::pin(
+        async move {
+            let conn = &mut state.db_pool.get().await?;
+
+            conn.transaction(|tx| {
+                let event = &event;
+                Box::pin(async move {
+                    if !inbox::record(tx, event).await? {
+                        return Ok(());
+                    }
+
+                    let payload = &event.payload;
+
+                    if payload.full {
+                        match order_status::transition(
+                            tx,
+                            payload.order_id,
+                            OrderStatus::Refunded,
+                            &Actor::event(event),
+                        )
+                        .await
+                        {
+                            // The refund asked for by `order_payment_success`, the stock
+                            // was already released when the order was closed
+                            Err(TransitionError::Illegal {
+                                from: from @ (OrderStatus::Cancelled | OrderStatus::Expired),
+                                ..
+                            }) => {
+                                info!(
+                                    %from,
+                                    amount_minor = payload.amount_minor,
+                                    "Payment of the closed order has been refunded"
+                                );
+                                return Ok(());
+                            }
+                            result => {
+                                result.map_err(TransitionError::into_consumer_error)?;
+                            }
+                        }
+
+                        outbox::publish(
+                            tx,
+                            &event.follow_up(
+                                SERVICE_NAME,
+                                InventoryReleaseRequestedEvent {
+                                    order_id: payload.order_id,
+                                },
+                            ),
+                        )
+                        .await?;
+
+                        metrics::counter!("orders_refunded_total").increment(1);
+                        info!(
+                            amount_minor = payload.amount_minor,
+                            "Order has been refunded in full"
+                        );
+                    } else if !payload.returned_items.is_empty() {
+                        outbox::publish(
+                            tx,
+                            &event.follow_up(
+                                SERVICE_NAME,
+                                InventoryRestockRequestedEvent {
+                                    order_id: payload.order_id,
+                                    items: payload.returned_items.clone(),
+                                },
+                            ),
+                        )
+                        .await?;
+
+                        info!(
+                            amount_minor = payload.amount_minor,
+                            items = payload.returned_items.len(),
+                            "Order has been partially refunded, restocking returned items"
+                        );
+                    } else {
+                        info!(
+                            amount_minor = payload.amount_minor,
+                            "Order has been partially refunded"
+                        );
+                    }
+
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .await?;
 
-        Ok(())
-    })
+            Ok(())
+        }
+        .instrument(span),
+    )
 }
@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use medbook_common::config::env_or;
+use tracing::{Instrument, error, info, info_span};
+
+use crate::{
+    app_state::AppState,
+    compensation,
+    models::OrderEntity,
+    order_history::Actor,
+    order_status::{self, OrderStatus},
+    schema::orders,
+};
+
+const JOB_NAME: &str = "order_expiry";
+
+/// Statuses in which an order holds stock without having been paid for.
+const EXPIRABLE: [OrderStatus; 2] = [OrderStatus::Reserved, OrderStatus::PaymentProcessing];
+
+#[derive(Clone, Debug)]
+pub struct OrderExpiryConfig {
+    /// How long an order may stay RESERVED or PAYMENT_PROCESSING before it expires.
+    pub ttl: Duration,
+    /// How often the job looks for expired orders.
+    pub interval: Duration,
+    /// Maximum number of orders expired per run.
+    pub batch_size: i64,
+}
+
+impl OrderExpiryConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            ttl: Duration::from_secs(env_or("ORDER_EXPIRY_TTL_SECS", 30 * 60)?),
+            interval: Duration::from_secs(env_or("ORDER_EXPIRY_INTERVAL_SECS", 60)?),
+            batch_size: env_or("ORDER_EXPIRY_BATCH_SIZE", 100)?,
+        })
+    }
+}
+
+/// Periodically moves unpaid orders whose status has not changed within the TTL
+/// to EXPIRED and hands their stock back, until the service shuts down.
+pub fn init(state: AppState, config: OrderExpiryConfig) {
+    info!("Order expiry job initialized");
+    let shutdown = state.shutdown.clone();
+
+    shutdown.clone().spawn(async move {
+        while !shutdown.is_cancelled() {
+            if let Err(e) = run(&state, &config).await {
+                error!(error = format!("{:#}", e), "Order expiry job failed");
+            }
+            shutdown.sleep(config.interval).await;
+        }
+    });
+}
+
+async fn run(state: &AppState, config: &OrderExpiryConfig) -> anyhow::Result<()> {
+    let cutoff = Utc::now() - config.ttl;
+    let conn = &mut state.db_pool.get().await?;
+
+    let order_ids: Vec<i32> = orders::table
+        .filter(orders::status.eq_any(EXPIRABLE))
+        .filter(orders::status_changed_at.lt(cutoff))
+        .order(orders::status_changed_at.asc())
+        .select(orders::id)
+        .limit(config.batch_size)
+        .load(conn)
+        .await?;
+
+    // One transaction per order, so one that fails does not hold back the others
+    for order_id in order_ids {
+        let span = info_span!("expire_order", order_id);
+        if let Err(e) = expire(conn, order_id, cutoff).instrument(span).await {
+            error!(error = format!("{:#}", e), "Failed to expire order");
+        }
+    }
+
+    Ok(())
+}
+
+async fn expire(
+    conn: &mut AsyncPgConnection,
+    order_id: i32,
+    cutoff: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    conn.transaction(|conn| {
+        Box::pin(async move {
+            // Checked again under lock: the order may have been paid or cancelled since
+            let Some(order): Option<OrderEntity> = orders::table
+                .find(order_id)
+                .filter(orders::status.eq_any(EXPIRABLE))
+                .filter(orders::status_changed_at.lt(cutoff))
+                .for_update()
+                .get_result(conn)
+                .await
+                .optional()
+                .context("Failed to fetch order")?
+            else {
+                return Ok(());
+            };
+
+            order_status::transition(
+                conn,
+                order_id,
+                OrderStatus::Expired,
+                &Actor::System(JOB_NAME),
+            )
+            .await?;
+
+            compensation::undo(conn, &order).await?;
+
+            metrics::counter!("orders_expired_total").increment(1);
+            info!(from = %order.status, "Order has expired");
+            Ok::<_, anyhow::Error>(())
+        })
+    })
+    .await
+}
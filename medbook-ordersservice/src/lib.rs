@@ -1,10 +1,16 @@
 pub mod app_error;
 pub mod app_state;
+pub mod compensation;
 pub mod config;
 pub mod consumers;
-pub mod db;
 pub mod infrastructure;
+pub mod jobs;
 pub mod models;
-pub mod outbox;
+pub mod order_history;
+pub mod order_status;
+pub mod payment_providers;
 pub mod routes;
 pub mod schema;
+
+/// Name stamped as `producer` on every event this service emits.
+pub const SERVICE_NAME: &str = "medbook-ordersservice";
@@ -1,47 +1,83 @@
-use medbook_ordersservice::{app_state, consumers, outbox, routes};
+use medbook_common::{
+    consumers::ConsumerConfig,
+    dead_letters, health,
+    outbox::{self, OutboxConfig},
+    prometheus, shutdown, telemetry,
+};
+use medbook_events::{
+    OrderPaymentSuccessEvent, OrderRejectedEvent, OrderReservedEvent, PaymentFailedEvent,
+    PaymentRefundedEvent,
+};
+use medbook_ordersservice::{
+    SERVICE_NAME, app_state, consumers,
+    jobs::order_expiry::{self, OrderExpiryConfig},
+    routes,
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
-        .init();
-
     dotenvy::dotenv().ok();
+    telemetry::init(SERVICE_NAME)?;
     tracing::info!(".env files loaded");
 
     let ip = format!("0.0.0.0:{}", std::env::var("PORT")?);
     tracing::info!("Starting OrdersService on {}...", ip);
 
+    prometheus::init()?;
     let app_state = app_state::AppState::init().await?;
+    let consumer_config = ConsumerConfig::from_env()?;
 
-    consumers::init(
-        "orders.order_reserved".into(),
+    medbook_common::consumers::consume::<OrderReservedEvent, _>(
         consumers::orders::order_reserved,
         app_state.clone(),
-    );
+        consumer_config.clone(),
+    )?;
 
-    consumers::init(
-        "orders.order_rejected".into(),
+    medbook_common::consumers::consume::<OrderRejectedEvent, _>(
         consumers::orders::order_rejected,
         app_state.clone(),
-    );
+        consumer_config.clone(),
+    )?;
 
-    consumers::init(
-        "orders.payment_success".into(),
+    medbook_common::consumers::consume::<OrderPaymentSuccessEvent, _>(
         consumers::orders::order_payment_success,
         app_state.clone(),
-    );
+        consumer_config.clone(),
+    )?;
+
+    medbook_common::consumers::consume::<PaymentFailedEvent, _>(
+        consumers::orders::order_payment_failed,
+        app_state.clone(),
+        consumer_config.clone(),
+    )?;
 
-    outbox::init(app_state.clone());
+    medbook_common::consumers::consume::<PaymentRefundedEvent, _>(
+        consumers::orders::order_payment_refunded,
+        app_state.clone(),
+        consumer_config.clone(),
+    )?;
+
+    outbox::init(app_state.clone(), OutboxConfig::from_env()?);
+    order_expiry::init(app_state.clone(), OrderExpiryConfig::from_env()?);
 
     let app = axum::Router::new()
         .nest("/orders", routes::orders::routes())
         .nest("/authtest", routes::authtest::routes())
+        .nest("/admin/dead-letters", dead_letters::routes()?)
+        .nest("/health", health::routes())
         .route("/health-check", axum::routing::get(|| async { "OK" }))
-        .with_state(app_state);
+        .nest("/metrics", prometheus::routes())
+        .layer(axum::middleware::from_fn(prometheus::track_http))
+        .layer(axum::middleware::from_fn(telemetry::trace_http))
+        .layer(axum::middleware::from_fn(telemetry::request_id))
+        .with_state(app_state.clone());
 
     let listener = tokio::net::TcpListener::bind(ip).await.unwrap();
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(app_state.shutdown.clone().signal())
+        .await?;
+
+    shutdown::drain(app_state).await?;
 
     Ok(())
 }
@@ -7,25 +7,29 @@ use serde::Serialize;
 use serde_json::Value;
 use uuid::Uuid;
 
+use crate::order_status::OrderStatus;
+
 #[derive(Queryable, Serialize, Selectable, Debug)]
 #[diesel(table_name = crate::schema::orders)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct OrderEntity {
     pub id: i32,
     pub patient_id: i32,
-    pub status: String,
+    pub status: OrderStatus,
     pub order_type: String,
     pub delivery_address: Option<Value>,
     pub payment_id: Option<Uuid>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub correlation_id: Uuid,
+    pub payment_attempts: i32,
+    pub status_changed_at: DateTime<Utc>,
 }
 
 #[derive(AsChangeset)]
 #[diesel(table_name = crate::schema::orders)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct UpdateOrderEntity {
-    pub status: Option<String>,
     pub payment_id: Option<Uuid>,
 }
 
@@ -34,7 +38,7 @@ pub struct UpdateOrderEntity {
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct CreateOrderEntity {
     pub patient_id: i32,
-    pub status: String,
+    pub status: OrderStatus,
 }
 
 #[derive(Queryable, Selectable, Debug, Serialize)]
@@ -66,20 +70,28 @@ pub struct OrderWithItems {
     pub items: Vec<OrderItemEntity>,
 }
 
-#[derive(Queryable, Selectable, Debug)]
-#[diesel(table_name = crate::schema::outbox)]
+#[derive(Queryable, Selectable, Serialize, Debug)]
+#[diesel(table_name = crate::schema::order_status_history)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct OutboxEntity {
-    pub id: i32,
-    pub event_type: String,
-    pub payload: String,
-    pub status: String,
+pub struct OrderStatusHistoryEntity {
+    pub id: i64,
+    pub order_id: i32,
+    pub from_status: Option<OrderStatus>,
+    pub to_status: OrderStatus,
+    pub actor_type: String,
+    pub actor: String,
+    pub event_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
 }
 
-#[derive(Queryable, Insertable, Selectable, Debug)]
-#[diesel(table_name = crate::schema::outbox)]
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::order_status_history)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct CreateOutboxEntity {
-    pub event_type: String,
-    pub payload: String,
+pub struct CreateOrderStatusHistoryEntity {
+    pub order_id: i32,
+    pub from_status: Option<OrderStatus>,
+    pub to_status: OrderStatus,
+    pub actor_type: String,
+    pub actor: String,
+    pub event_id: Option<Uuid>,
 }
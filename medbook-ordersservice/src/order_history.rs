@@ -0,0 +1,82 @@
+use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use medbook_events::{Event, EventEnvelope};
+use uuid::Uuid;
+
+use crate::{
+    models::{CreateOrderStatusHistoryEntity, OrderStatusHistoryEntity},
+    order_status::OrderStatus,
+    schema::order_status_history,
+};
+
+/// Who or what caused a status change.
+#[derive(Debug, Clone)]
+pub enum Actor {
+    /// A consumed event.
+    Event {
+        event_type: &'static str,
+        event_id: Uuid,
+    },
+    /// A patient, through the HTTP API.
+    Patient(i32),
+    /// A background job of this service.
+    System(&'static str),
+}
+
+impl Actor {
+    pub fn event<E: Event>(envelope: &EventEnvelope<E>) -> Self {
+        Actor::Event {
+            event_type: E::TYPE,
+            event_id: envelope.event_id,
+        }
+    }
+}
+
+/// Appends a status change of order `order_id` to its timeline. `from` is `None`
+/// when the order was just created.
+pub async fn record(
+    conn: &mut AsyncPgConnection,
+    order_id: i32,
+    from: Option<OrderStatus>,
+    to: OrderStatus,
+    actor: &Actor,
+) -> Result<(), diesel::result::Error> {
+    let (actor_type, actor, event_id) = match actor {
+        Actor::Event {
+            event_type,
+            event_id,
+        } => ("EVENT", event_type.to_string(), Some(*event_id)),
+        Actor::Patient(patient_id) => ("PATIENT", patient_id.to_string(), None),
+        Actor::System(job) => ("SYSTEM", job.to_string(), None),
+    };
+
+    diesel::insert_into(order_status_history::table)
+        .values(CreateOrderStatusHistoryEntity {
+            order_id,
+            from_status: from,
+            to_status: to,
+            actor_type: actor_type.into(),
+            actor,
+            event_id,
+        })
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Status changes of order `order_id`, oldest first.
+pub async fn timeline(
+    conn: &mut AsyncPgConnection,
+    order_id: i32,
+) -> Result<Vec<OrderStatusHistoryEntity>, diesel::result::Error> {
+    order_status_history::table
+        .filter(order_status_history::order_id.eq(order_id))
+        .order((
+            order_status_history::created_at.asc(),
+            order_status_history::id.asc(),
+        ))
+        .select(OrderStatusHistoryEntity::as_select())
+        .load(conn)
+        .await
+}
@@ -0,0 +1,240 @@
+use std::{fmt, io::Write};
+
+use diesel::{
+    ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper,
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    serialize::{self, IsNull, Output, ToSql},
+    sql_types::Text,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use medbook_common::consumers::Poison;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    models::OrderEntity,
+    order_history::{self, Actor},
+    schema::orders,
+};
+
+/// Where an order is in the order saga. Stored as text, guarded by the
+/// `orders_status_check` constraint.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OrderStatus {
+    /// Created, waiting for the inventory to reserve its items.
+    Pending,
+    Reserved,
+    /// Not enough stock for at least one item.
+    Rejected,
+    /// A payment was requested for the order.
+    PaymentProcessing,
+    PaymentSuccess,
+    /// Every payment attempt failed; the stock was released.
+    PaymentFailed,
+    /// Cancelled by the patient before it was paid for.
+    Cancelled,
+    /// Not paid for in time, see `jobs::order_expiry`.
+    Expired,
+    /// Paid for, then refunded in full; the stock was released.
+    Refunded,
+}
+
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Pending => "PENDING",
+            OrderStatus::Reserved => "RESERVED",
+            OrderStatus::Rejected => "REJECTED",
+            OrderStatus::PaymentProcessing => "PAYMENT_PROCESSING",
+            OrderStatus::PaymentSuccess => "PAYMENT_SUCCESS",
+            OrderStatus::PaymentFailed => "PAYMENT_FAILED",
+            OrderStatus::Cancelled => "CANCELLED",
+            OrderStatus::Expired => "EXPIRED",
+            OrderStatus::Refunded => "REFUNDED",
+        }
+    }
+
+    /// Whether an order may move from `self` to `to`.
+    pub fn can_transition_to(&self, to: OrderStatus) -> bool {
+        use OrderStatus::*;
+
+        matches!(
+            (self, to),
+            (Pending, Reserved)
+                | (Pending, Rejected)
+                | (Reserved, PaymentProcessing)
+                | (PaymentProcessing, PaymentSuccess)
+                // A failed payment may be retried until the attempts run out
+                | (PaymentProcessing, Reserved)
+                | (PaymentProcessing, PaymentFailed)
+                | (Pending | Reserved | PaymentProcessing, Cancelled)
+                | (Reserved | PaymentProcessing, Expired)
+                | (PaymentSuccess, Refunded)
+        )
+    }
+}
+
+impl fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ToSql<Text, Pg> for OrderStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for OrderStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match <String as FromSql<Text, Pg>>::from_sql(bytes)?.as_str() {
+            "PENDING" => Ok(OrderStatus::Pending),
+            "RESERVED" => Ok(OrderStatus::Reserved),
+            "REJECTED" => Ok(OrderStatus::Rejected),
+            "PAYMENT_PROCESSING" => Ok(OrderStatus::PaymentProcessing),
+            "PAYMENT_SUCCESS" => Ok(OrderStatus::PaymentSuccess),
+            "PAYMENT_FAILED" => Ok(OrderStatus::PaymentFailed),
+            "CANCELLED" => Ok(OrderStatus::Cancelled),
+            "EXPIRED" => Ok(OrderStatus::Expired),
+            "REFUNDED" => Ok(OrderStatus::Refunded),
+            status => Err(format!("Unknown order status \"{}\"", status).into()),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TransitionError {
+    #[error("Order #{0} does not exist")]
+    NotFound(i32),
+
+    #[error("Order #{order_id} cannot move from {from} to {to}")]
+    Illegal {
+        order_id: i32,
+        from: OrderStatus,
+        to: OrderStatus,
+    },
+
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+}
+
+impl TransitionError {
+    /// For consumers: a missing order or an illegal move will fail the same way on
+    /// every retry, so it is dead-lettered right away.
+    pub fn into_consumer_error(self) -> anyhow::Error {
+        match self {
+            TransitionError::Database(e) => e.into(),
+            e => Poison(e.into()).into(),
+        }
+    }
+}
+
+/// Moves order `order_id` to `to`, if its current status allows it, and records
+/// the change with its `actor` in the order's timeline. The row is locked until
+/// the end of the transaction `conn` is in, so concurrent moves of the same order
+/// are checked one after the other.
+pub async fn transition(
+    conn: &mut AsyncPgConnection,
+    order_id: i32,
+    to: OrderStatus,
+    actor: &Actor,
+) -> Result<OrderEntity, TransitionError> {
+    let from: OrderStatus = orders::table
+        .find(order_id)
+        .select(orders::status)
+        .for_update()
+        .get_result(conn)
+        .await
+        .optional()?
+        .ok_or(TransitionError::NotFound(order_id))?;
+
+    if !from.can_transition_to(to) {
+        return Err(TransitionError::Illegal { order_id, from, to });
+    }
+
+    let order = diesel::update(orders::table.find(order_id))
+        .set((
+            orders::status.eq(to),
+            orders::status_changed_at.eq(diesel::dsl::now),
+        ))
+        .returning(OrderEntity::as_returning())
+        .get_result(conn)
+        .await?;
+
+    order_history::record(conn, order_id, Some(from), to, actor).await?;
+
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrderStatus::{self, *};
+
+    const ALL: [OrderStatus; 9] = [
+        Pending,
+        Reserved,
+        Rejected,
+        PaymentProcessing,
+        PaymentSuccess,
+        PaymentFailed,
+        Cancelled,
+        Expired,
+        Refunded,
+    ];
+
+    /// Every allowed move; any pair missing here must be refused.
+    const ALLOWED: [(OrderStatus, OrderStatus); 12] = [
+        (Pending, Reserved),
+        (Pending, Rejected),
+        (Pending, Cancelled),
+        (Reserved, PaymentProcessing),
+        (Reserved, Cancelled),
+        (Reserved, Expired),
+        (PaymentProcessing, PaymentSuccess),
+        (PaymentProcessing, Reserved),
+        (PaymentProcessing, PaymentFailed),
+        (PaymentProcessing, Cancelled),
+        (PaymentProcessing, Expired),
+        (PaymentSuccess, Refunded),
+    ];
+
+    #[test]
+    fn allows_only_the_listed_transitions() {
+        for from in ALL {
+            for to in ALL {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    ALLOWED.contains(&(from, to)),
+                    "{} -> {}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_notable_transitions() {
+        for (from, to) in [
+            (Pending, PaymentProcessing),
+            (Reserved, PaymentSuccess),
+            (PaymentSuccess, Cancelled),
+            (PaymentSuccess, Expired),
+            (Cancelled, PaymentSuccess),
+            (Expired, PaymentSuccess),
+            (Refunded, PaymentSuccess),
+            (Rejected, Reserved),
+            (Pending, Pending),
+        ] {
+            assert!(!from.can_transition_to(to), "{} -> {}", from, to);
+        }
+    }
+}
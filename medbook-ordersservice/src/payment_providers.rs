@@ -0,0 +1,25 @@
+use medbook_common::app_error::BaseError;
+use serde::Deserialize;
+
+use crate::app_state::AppState;
+
+#[derive(Deserialize, Debug)]
+struct ProviderInfo {
+    name: String,
+}
+
+/// Names of the providers the payment service currently accepts payments with.
+pub async fn available(state: &AppState) -> Result<Vec<String>, BaseError> {
+    let providers: Vec<ProviderInfo> = state
+        .http_client
+        .get(format!("{}/payments/providers", state.payment_service_url))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|_| BaseError::ServiceUnreachable("PaymentService".into()))?
+        .json()
+        .await
+        .map_err(|_| BaseError::ServiceUnreachable("PaymentService".into()))?;
+
+    Ok(providers.into_iter().map(|p| p.name).collect())
+}
@@ -8,22 +8,28 @@ use axum::{
     response::IntoResponse,
     routing,
 };
-use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper};
 use diesel_async::{AsyncConnection, RunQueryDsl};
-use medbook_events::OrderPayRequestEvent;
+use medbook_common::{app_error::BaseError, outbox};
+use medbook_events::{EventEnvelope, OrderItem, OrderPayRequestEvent, OrderRequestedEvent};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use tracing::{Span, field, info, instrument};
 use uuid::Uuid;
 
 use crate::{
+    SERVICE_NAME,
     app_error::AppError,
     app_state::AppState,
+    compensation,
     infrastructure::axum_http::middleware::patients_authorization,
     models::{
-        CreateOrderEntity, CreateOrderItemEntity, CreateOutboxEntity, OrderEntity, OrderItemEntity,
-        OrderWithItems, OutboxEntity, UpdateOrderEntity,
+        CreateOrderEntity, CreateOrderItemEntity, OrderEntity, OrderItemEntity, OrderWithItems,
+        UpdateOrderEntity,
     },
-    schema::{order_items, orders, outbox},
+    order_history::{self, Actor},
+    order_status::{self, OrderStatus},
+    payment_providers,
+    schema::{order_items, orders},
 };
 
 pub fn routes() -> Router<AppState> {
@@ -32,21 +38,11 @@ pub fn routes() -> Router<AppState> {
         .route("/", routing::get(get_orders))
         .route("/{id}", routing::get(get_order_by_id))
         .route("/{id}/pay", routing::post(pay_for_order_id))
+        .route("/{id}/cancel", routing::post(cancel_order))
+        .route("/{id}/timeline", routing::get(get_order_timeline))
         .route_layer(middleware::from_fn(patients_authorization))
 }
 
-#[derive(Serialize, Debug)]
-struct OrderRequestedEvent {
-    order_id: i32,
-    order_items: Vec<OrderItem>,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct OrderItem {
-    product_id: i32,
-    quantity: i32,
-}
-
 #[derive(Deserialize, Debug)]
 struct CreateOrderReq {
     pub order_items: Vec<OrderItem>,
@@ -60,6 +56,7 @@ pub struct ProductEntity {
     pub unit_price: f32,
 }
 
+#[instrument(skip_all, fields(patient_id = patient_id, order_id = field::Empty))]
 async fn create_order(
     State(state): State<AppState>,
     Extension(patient_id): Extension<i32>,
@@ -81,7 +78,7 @@ async fn create_order(
         .get(format!("http://localhost:3000/products"))
         .send()
         .await
-        .map_err(|_| AppError::ServiceUnreachable("ProductsService".into()))?
+        .map_err(|_| BaseError::ServiceUnreachable("ProductsService".into()))?
         .json()
         .await
         .context("Failed to parse response as text")?;
@@ -99,14 +96,24 @@ async fn create_order(
                 let created_order: OrderEntity = diesel::insert_into(orders::table)
                     .values(CreateOrderEntity {
                         patient_id,
-                        status: "PENDING".into(),
+                        status: OrderStatus::Pending,
                     })
                     .returning(OrderEntity::as_returning())
                     .get_result(tx)
                     .await
                     .context("Failed to create order")?;
 
-                info!("Order {} has been created", created_order.id);
+                Span::current().record("order_id", created_order.id);
+                order_history::record(
+                    tx,
+                    created_order.id,
+                    None,
+                    OrderStatus::Pending,
+                    &Actor::Patient(patient_id),
+                )
+                .await
+                .context("Failed to record order status")?;
+                info!("Order has been created");
 
                 let (insert_items, order_items): (Vec<CreateOrderItemEntity>, Vec<OrderItem>) =
                     order_items
@@ -141,30 +148,20 @@ async fn create_order(
                     .await
                     .context("Failed to insert order items")?;
 
-                info!(
-                    "{} items of order {} have been created",
-                    inserted_count, created_order.id
-                );
-
-                let outbox = diesel::insert_into(outbox::table)
-                    .values(CreateOutboxEntity {
-                        event_type: "inventory.order_requested".into(),
-                        payload: serde_json::to_string(&OrderRequestedEvent {
-                            order_id: created_order.id,
-                            order_items: order_items,
-                        })?,
-                    })
-                    .returning(OutboxEntity::as_returning())
-                    .get_result(tx)
-                    .await?;
+                info!(items = inserted_count, "Order items have been created");
+
+                let envelope = EventEnvelope::new(
+                    SERVICE_NAME,
+                    OrderRequestedEvent {
+                        order_id: created_order.id,
+                        order_items: order_items,
+                    },
+                )
+                .with_correlation_id(created_order.correlation_id);
 
-                info!(
-                    "{} items of order {} have been created",
-                    inserted_count, created_order.id
-                );
+                outbox::publish(tx, &envelope).await?;
 
-                info!("Committed order {} and its items", created_order.id);
-                info!("Outbox created: {:?}", outbox);
+                info!("Committed order and its items");
 
                 Ok::<_, anyhow::Error>(created_order)
             })
@@ -172,6 +169,8 @@ async fn create_order(
         .await
         .context("Failed to create order and its items in a transaction")?;
 
+    metrics::counter!("orders_created_total").increment(1);
+
     Ok(Json(created_order))
 }
 
@@ -213,6 +212,7 @@ async fn get_orders(State(state): State<AppState>) -> Result<impl IntoResponse,
     Ok(Json(orders_with_items))
 }
 
+#[instrument(skip_all, fields(patient_id = patient_id, order_id = id))]
 async fn get_order_by_id(
     Path(id): Path<i32>,
     Extension(patient_id): Extension<i32>,
@@ -231,10 +231,7 @@ async fn get_order_by_id(
         .context("Failed to fetch order")?;
 
     if order.patient_id != patient_id {
-        return Err(AppError::ForbiddenResource(format!(
-            "orders(id={})",
-            order.id
-        )));
+        return Err(BaseError::ForbiddenResource(format!("orders(id={})", order.id)).into());
     }
 
     let items: Vec<OrderItemEntity> = order_items::table
@@ -249,6 +246,38 @@ async fn get_order_by_id(
     Ok(Json(order_with_items))
 }
 
+#[instrument(skip_all, fields(patient_id = patient_id, order_id = id))]
+async fn get_order_timeline(
+    Path(id): Path<i32>,
+    Extension(patient_id): Extension<i32>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let conn = &mut state
+        .db_pool
+        .get()
+        .await
+        .context("Failed to obtain a DB connection pool")?;
+
+    let order_patient_id: i32 = orders::table
+        .find(id)
+        .select(orders::patient_id)
+        .get_result(conn)
+        .await
+        .optional()
+        .context("Failed to fetch order")?
+        .ok_or_else(|| BaseError::NotFound(format!("orders(id={})", id)))?;
+
+    if order_patient_id != patient_id {
+        return Err(BaseError::ForbiddenResource(format!("orders(id={})", id)).into());
+    }
+
+    let timeline = order_history::timeline(conn, id)
+        .await
+        .context("Failed to fetch order timeline")?;
+
+    Ok(Json(timeline))
+}
+
 #[derive(Deserialize)]
 pub struct PayForOrderReq {
     pub provider: String,
@@ -260,6 +289,7 @@ pub struct PayForOrderRes {
     pub payment_id: Uuid,
 }
 
+#[instrument(skip_all, fields(patient_id = patient_id, order_id = id, payment_id = field::Empty))]
 async fn pay_for_order_id(
     State(state): State<AppState>,
     Extension(patient_id): Extension<i32>,
@@ -272,10 +302,11 @@ async fn pay_for_order_id(
         .await
         .context("Failed to obtain a DB connection pool")?;
 
-    // Check provider
-    match body.provider.as_str() {
-        "qr_payment" => {}
-        _ => return Err(AppError::InvalidPaymentProvider(body.provider)),
+    if !payment_providers::available(&state)
+        .await?
+        .contains(&body.provider)
+    {
+        return Err(AppError::InvalidPaymentProvider(body.provider));
     }
 
     let updated_order = conn
@@ -283,32 +314,44 @@ async fn pay_for_order_id(
             Box::pin(async move {
                 // Generate payment UUID
                 let payment_id = Uuid::new_v4();
+                Span::current().record("payment_id", field::display(payment_id));
 
                 // 1. Update status to PAYMENT_PROCESSING
-                let updated_order: OrderEntity = diesel::update(
-                    orders::table
-                        .filter(orders::id.eq(id))
-                        .filter(orders::status.eq("RESERVED")),
+                let order: OrderEntity = orders::table
+                    .find(id)
+                    .get_result(conn)
+                    .await
+                    .optional()
+                    .context("Failed to fetch order")?
+                    .ok_or_else(|| BaseError::NotFound(format!("orders(id={})", id)))?;
+
+                if order.patient_id != patient_id {
+                    return Err(
+                        BaseError::ForbiddenResource(format!("orders(id={})", order.id)).into(),
+                    );
+                }
+
+                order_status::transition(
+                    conn,
+                    id,
+                    OrderStatus::PaymentProcessing,
+                    &Actor::Patient(patient_id),
                 )
-                .set(&UpdateOrderEntity {
-                    status: Some("PAYMENT_PROCESSING".into()),
-                    payment_id: Some(payment_id),
-                })
-                .returning(OrderEntity::as_returning())
-                .get_result(conn)
-                .await
-                .context("Unable to fetch updated order")?;
+                .await?;
+
+                let updated_order: OrderEntity = diesel::update(orders::table.find(id))
+                    .set((
+                        &UpdateOrderEntity {
+                            payment_id: Some(payment_id),
+                        },
+                        orders::payment_attempts.eq(orders::payment_attempts + 1),
+                    ))
+                    .returning(OrderEntity::as_returning())
+                    .get_result(conn)
+                    .await
+                    .context("Unable to fetch updated order")?;
 
-                if updated_order.patient_id != patient_id {
-                    return Err(AppError::ForbiddenResource(format!(
-                        "orders(id={})",
-                        updated_order.id
-                    )));
-                }
-                info!(
-                    "Updated order #{}'s status to PAYMENT_PROCESSING",
-                    updated_order.id
-                );
+                info!("Updated order's status to PAYMENT_PROCESSING");
 
                 // 2. Calculate total price
                 let order_items: Vec<OrderItemEntity> = order_items::table
@@ -317,31 +360,30 @@ async fn pay_for_order_id(
                     .await
                     .context("Failed to get order items")?;
 
-                let total_price = order_items
-                    .into_iter()
-                    .map(|item| item.total_price)
-                    .sum::<f32>();
+                let total_price = order_items.iter().map(|item| item.total_price).sum::<f32>();
 
-                info!("Price: {}", total_price);
+                info!(total_price, "Calculated the order's total price");
 
                 // 3. Create outbox
-                let outbox = diesel::insert_into(outbox::table)
-                    .values(CreateOutboxEntity {
-                        event_type: "payments.pay_request".into(),
-                        payload: serde_json::to_string(&OrderPayRequestEvent {
-                            payment_id,
-                            order_id: id,
-                            amount: total_price,
-                            provider: "qr_payment".into(),
-                        })
-                        .context("Failed to serialize OrderPayEvent")?,
-                    })
-                    .returning(OutboxEntity::as_returning())
-                    .get_result(conn)
-                    .await
-                    .context("Outbox creation failed")?;
+                let envelope = EventEnvelope::new(
+                    SERVICE_NAME,
+                    OrderPayRequestEvent {
+                        payment_id,
+                        order_id: id,
+                        amount: total_price,
+                        provider: body.provider,
+                        items: order_items
+                            .iter()
+                            .map(|item| OrderItem {
+                                product_id: item.product_id,
+                                quantity: item.quantity,
+                            })
+                            .collect(),
+                    },
+                )
+                .with_correlation_id(updated_order.correlation_id);
 
-                info!("Outbox created: {:?}", outbox);
+                outbox::publish(conn, &envelope).await?;
 
                 Ok::<OrderEntity, AppError>(updated_order)
             })
@@ -353,3 +395,58 @@ async fn pay_for_order_id(
         Err(err) => Err(err),
     }
 }
+
+/// Cancels an order that has not been paid for yet. Whatever was already done for
+/// it is undone asynchronously, see [`compensation::undo`].
+#[instrument(skip_all, fields(patient_id = patient_id, order_id = id))]
+async fn cancel_order(
+    State(state): State<AppState>,
+    Extension(patient_id): Extension<i32>,
+    Path(id): Path<i32>,
+) -> Result<impl IntoResponse, AppError> {
+    let conn = &mut state
+        .db_pool
+        .get()
+        .await
+        .context("Failed to obtain a DB connection pool")?;
+
+    let cancelled_order = conn
+        .transaction(|conn| {
+            Box::pin(async move {
+                // Locked so the status cannot change between here and the transition
+                let order: OrderEntity = orders::table
+                    .find(id)
+                    .for_update()
+                    .get_result(conn)
+                    .await
+                    .optional()
+                    .context("Failed to fetch order")?
+                    .ok_or_else(|| BaseError::NotFound(format!("orders(id={})", id)))?;
+
+                if order.patient_id != patient_id {
+                    return Err(
+                        BaseError::ForbiddenResource(format!("orders(id={})", order.id)).into(),
+                    );
+                }
+
+                let cancelled_order = order_status::transition(
+                    conn,
+                    id,
+                    OrderStatus::Cancelled,
+                    &Actor::Patient(patient_id),
+                )
+                .await?;
+
+                compensation::undo(conn, &order).await?;
+
+                info!(from = %order.status, "Order has been cancelled");
+
+                Ok::<OrderEntity, AppError>(cancelled_order)
+            })
+        })
+        .await?;
+
+    metrics::counter!("orders_cancelled_total").increment(1);
+
+    Ok(Json(cancelled_order))
+}
@@ -12,6 +12,19 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    order_status_history (id) {
+        id -> Int8,
+        order_id -> Int4,
+        from_status -> Nullable<Text>,
+        to_status -> Text,
+        actor_type -> Text,
+        actor -> Text,
+        event_id -> Nullable<Uuid>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     orders (id) {
         id -> Int4,
@@ -22,18 +35,13 @@ diesel::table! {
         payment_id -> Nullable<Uuid>,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
-    }
-}
-
-diesel::table! {
-    outbox (id) {
-        id -> Int4,
-        event_type -> Text,
-        payload -> Text,
-        status -> Text,
+        correlation_id -> Uuid,
+        payment_attempts -> Int4,
+        status_changed_at -> Timestamptz,
     }
 }
 
 diesel::joinable!(order_items -> orders (order_id));
+diesel::joinable!(order_status_history -> orders (order_id));
 
-diesel::allow_tables_to_appear_in_same_query!(order_items, orders, outbox,);
+diesel::allow_tables_to_appear_in_same_query!(order_items, order_status_history, orders,);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+
+ALTER TABLE payments ADD COLUMN correlation_id UUID NOT NULL DEFAULT gen_random_uuid();
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE "outbox"
+  DROP COLUMN "attempts",
+  DROP COLUMN "last_error",
+  DROP COLUMN "next_attempt_at";
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+
+ALTER TABLE "outbox"
+  ADD COLUMN "attempts" integer NOT NULL DEFAULT 0,
+  ADD COLUMN "last_error" text,
+  ADD COLUMN "next_attempt_at" TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+-- status: PENDING, PROCESSED, FAILED (gave up after too many attempts)
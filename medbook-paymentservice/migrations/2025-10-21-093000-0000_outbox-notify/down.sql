@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+DROP TRIGGER IF EXISTS outbox_notify ON "outbox";
+DROP FUNCTION IF EXISTS outbox_notify();
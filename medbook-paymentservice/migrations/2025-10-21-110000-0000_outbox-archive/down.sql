@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+
+DROP TABLE "outbox_archive";
+
+DROP INDEX "outbox_pending_idx";
+
+ALTER TABLE "outbox"
+  DROP COLUMN "created_at",
+  DROP COLUMN "processed_at";
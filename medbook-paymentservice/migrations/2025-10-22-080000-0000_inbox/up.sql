@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+-- One row per consumed event, written in the same transaction as the handler's
+-- side effects, so a redelivered event is recognised and skipped.
+CREATE TABLE "inbox" (
+  "event_id" UUID PRIMARY KEY,
+  "event_type" text NOT NULL,
+  "processed_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
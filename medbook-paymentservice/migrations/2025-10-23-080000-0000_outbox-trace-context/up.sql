@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+-- W3C trace context (JSON object of propagation headers) of the span that
+-- inserted the row, so the relay can continue the trace when publishing.
+ALTER TABLE "outbox" ADD COLUMN "trace_context" text;
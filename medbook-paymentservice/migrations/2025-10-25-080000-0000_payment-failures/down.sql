@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX payments_status_created_at_idx;
+
+DROP INDEX payments_order_id_live_idx;
+
+ALTER TABLE payments ADD CONSTRAINT payments_order_id_key UNIQUE (order_id);
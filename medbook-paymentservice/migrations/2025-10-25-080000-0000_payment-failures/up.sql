@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+-- An order may be paid again after a failed attempt, so only one payment per
+-- order may be live at a time.
+ALTER TABLE payments DROP CONSTRAINT payments_order_id_key;
+
+CREATE UNIQUE INDEX payments_order_id_live_idx ON payments (order_id)
+WHERE status IN ('PENDING', 'SUCCESS');
+
+-- For the expiry job
+CREATE INDEX payments_status_created_at_idx ON payments (status, created_at);
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+
+CREATE TABLE refunds (
+  id                UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+  payment_id        UUID NOT NULL REFERENCES payments (id),
+  amount            REAL NOT NULL CHECK (amount > 0),
+  reason            TEXT,
+
+  status            VARCHAR(32) NOT NULL DEFAULT 'PENDING', -- PENDING, SUCCEEDED, FAILED
+  provider_ref      VARCHAR(128),  -- external refund reference
+  failure_reason    TEXT,
+  created_at        TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at        TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX refunds_payment_id_idx ON refunds (payment_id);
+
+CREATE TRIGGER update_refund_timestamp
+BEFORE UPDATE ON refunds
+FOR EACH ROW
+EXECUTE FUNCTION update_timestamp();
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX payments_provider_ref_idx;
+
+DROP TABLE webhook_events;
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+
+-- Webhooks already handled, so redeliveries are acknowledged without effect
+CREATE TABLE webhook_events (
+  provider          VARCHAR(64) NOT NULL,
+  event_id          VARCHAR(128) NOT NULL,
+  payment_id        UUID REFERENCES payments (id),
+  received_at       TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (provider, event_id)
+);
+
+CREATE UNIQUE INDEX payments_provider_ref_idx ON payments (provider, provider_ref);
@@ -0,0 +1,12 @@
+-- This file should undo anything in `up.sql`
+
+DROP TABLE refund_items;
+DROP TABLE payment_items;
+
+DROP INDEX refunds_status_created_at_idx;
+
+ALTER TABLE refunds ADD COLUMN amount REAL;
+UPDATE refunds SET amount = amount_minor / 100.0;
+ALTER TABLE refunds ALTER COLUMN amount SET NOT NULL;
+ALTER TABLE refunds ADD CONSTRAINT refunds_amount_check CHECK (amount > 0);
+ALTER TABLE refunds DROP COLUMN amount_minor;
@@ -0,0 +1,27 @@
+-- Your SQL goes here
+
+-- Refunds are counted in satang so they add up exactly to the payment
+ALTER TABLE refunds ADD COLUMN amount_minor BIGINT;
+UPDATE refunds SET amount_minor = ROUND(amount * 100);
+ALTER TABLE refunds ALTER COLUMN amount_minor SET NOT NULL;
+ALTER TABLE refunds ADD CONSTRAINT refunds_amount_minor_check CHECK (amount_minor > 0);
+ALTER TABLE refunds DROP COLUMN amount;
+
+-- For the job settling refunds left PENDING
+CREATE INDEX refunds_status_created_at_idx ON refunds (status, created_at);
+
+-- What a payment paid for, to check returned items against
+CREATE TABLE payment_items (
+  payment_id        UUID NOT NULL REFERENCES payments (id),
+  product_id        INTEGER NOT NULL,
+  quantity          INTEGER NOT NULL CHECK (quantity > 0),
+  PRIMARY KEY (payment_id, product_id)
+);
+
+-- Items handed back with a refund
+CREATE TABLE refund_items (
+  refund_id         UUID NOT NULL REFERENCES refunds (id),
+  product_id        INTEGER NOT NULL,
+  quantity          INTEGER NOT NULL CHECK (quantity > 0),
+  PRIMARY KEY (refund_id, product_id)
+);
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+-- Set when the provider took the money after the payment was voided or failed,
+-- the payment is then refunded on its own
+ALTER TABLE payments ADD COLUMN captured_late_at TIMESTAMPTZ;
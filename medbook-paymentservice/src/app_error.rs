@@ -1,34 +1,72 @@
-use std::error::Error;
-
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
+use diesel::result::Error as DieselError;
+use medbook_common::app_error::{BaseError, error_response};
 use thiserror::Error;
-use tracing::error;
+use uuid::Uuid;
 
 #[derive(Error, Debug)]
 pub enum AppError {
-    #[error("Service \"{0}\" is unreachable")]
-    ServiceUnreachable(String),
+    #[error("Payment {0} is {1} and cannot be refunded")]
+    NotRefundable(Uuid, String),
+
+    #[error(
+        "Refund amount {requested} must be positive and at most the refundable {refundable} satang"
+    )]
+    InvalidRefundAmount { requested: i64, refundable: i64 },
+
+    #[error("Returned items do not match the payment: {0}")]
+    InvalidReturnedItems(String),
+
+    #[error("Payment {0} is {1} and can no longer be paid")]
+    NotPayable(Uuid, String),
+
+    #[error("Webhook signature is missing, invalid or expired")]
+    InvalidWebhook,
+
+    #[error("Refund {0} was declined by the payment provider")]
+    RefundFailed(Uuid),
 
     #[error(transparent)]
-    Other(#[from] anyhow::Error),
+    Base(#[from] BaseError),
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        error!("Error: {}", self.to_string());
-        error!("Detailed error: {:#?}", self.source());
-
-        let (status, message) = match &self {
-            AppError::ServiceUnreachable(_) => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
-            AppError::Other(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal server error".into(),
-            ),
-        };
-
-        (status, message).into_response()
+        match self {
+            AppError::Base(err) => err.into_response(),
+            AppError::NotRefundable(..) => {
+                error_response(&self, StatusCode::CONFLICT, self.to_string())
+            }
+            AppError::InvalidRefundAmount { .. } => {
+                error_response(&self, StatusCode::BAD_REQUEST, self.to_string())
+            }
+            AppError::InvalidReturnedItems(_) => {
+                error_response(&self, StatusCode::BAD_REQUEST, self.to_string())
+            }
+            AppError::NotPayable(..) => {
+                error_response(&self, StatusCode::CONFLICT, self.to_string())
+            }
+            AppError::InvalidWebhook => {
+                error_response(&self, StatusCode::UNAUTHORIZED, self.to_string())
+            }
+            AppError::RefundFailed(_) => {
+                error_response(&self, StatusCode::BAD_GATEWAY, self.to_string())
+            }
+        }
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        AppError::Base(err.into())
+    }
+}
+
+impl From<DieselError> for AppError {
+    fn from(err: DieselError) -> Self {
+        AppError::Base(err.into())
     }
 }
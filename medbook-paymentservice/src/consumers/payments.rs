@@ -1,45 +1,308 @@
-use anyhow::{Context, Result};
-use diesel::SelectableHelper;
-use diesel_async::RunQueryDsl;
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, anyhow};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::{AsyncConnection, RunQueryDsl};
 use futures::future::BoxFuture;
-use lapin::{message::Delivery, options::BasicAckOptions};
-use medbook_events::OrderPayRequestEvent;
-use tracing::info;
+use medbook_common::{consumers::Poison, inbox};
+use medbook_events::{
+    EventEnvelope, OrderPayRequestEvent, PaymentRefundRequestedEvent, PaymentVoidRequestedEvent,
+};
+use tracing::{Instrument, info, info_span, warn};
 
 use crate::{
+    app_error::AppError,
     app_state::AppState,
-    models::{CreatePaymentEntity, PaymentEntity},
-    schema::payments,
+    models::{CreatePaymentEntity, CreatePaymentItemEntity, PaymentEntity},
+    payment_failure, payment_success,
+    providers::ChargeStatus,
+    refunds::{self, RefundRequest},
+    schema::{payment_items, payments},
 };
 
-pub fn pay_request(delivery: Delivery, state: AppState) -> BoxFuture<'static, Result<()>> {
-    Box::pin(async move {
-        let payload: OrderPayRequestEvent = serde_json::from_str(str::from_utf8(&delivery.data)?)?;
-        info!("Received event: {:?}", payload);
-
-        let conn = &mut state
-            .db_pool
-            .get()
-            .await
-            .context("Failed to obtain a DB connection pool")?;
-
-        let payment: PaymentEntity = diesel::insert_into(payments::table)
-            .values(CreatePaymentEntity {
-                id: payload.payment_id,
-                order_id: payload.order_id,
-                amount: payload.amount,
-                provider: payload.provider,
-                status: "PENDING".into(),
+/// Creates the payment of an order and its charge with the provider.
+///
+/// The provider is called between two transactions, so no connection or lock is
+/// held while it answers. A redelivery finds the payment created by the first
+/// and charges it again, with the payment id as the idempotency key.
+pub fn pay_request(
+    event: EventEnvelope<OrderPayRequestEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!(
+        "pay_request",
+        order_id = event.payload.order_id,
+        payment_id = %event.payload.payment_id,
+    );
+    Box::pin(
+        async move {
+            let conn = &mut state
+                .db_pool
+                .get()
+                .await
+                .context("Failed to obtain a DB connection pool")?;
+
+            let payment = conn
+                .transaction(|tx| {
+                    let event = &event;
+                    Box::pin(async move {
+                        let payload = &event.payload;
+
+                        diesel::insert_into(payments::table)
+                            .values(CreatePaymentEntity {
+                                id: payload.payment_id,
+                                order_id: payload.order_id,
+                                amount: payload.amount,
+                                provider: payload.provider.clone(),
+                                status: "PENDING".into(),
+                                correlation_id: event.correlation_id,
+                            })
+                            .on_conflict(payments::id)
+                            .do_nothing()
+                            .execute(tx)
+                            .await
+                            .context("Failed to create payment")?;
+
+                        let mut quantities: BTreeMap<i32, i32> = BTreeMap::new();
+                        for item in &payload.items {
+                            *quantities.entry(item.product_id).or_default() += item.quantity;
+                        }
+                        let items: Vec<CreatePaymentItemEntity> = quantities
+                            .into_iter()
+                            .map(|(product_id, quantity)| CreatePaymentItemEntity {
+                                payment_id: payload.payment_id,
+                                product_id,
+                                quantity,
+                            })
+                            .collect();
+
+                        if !items.is_empty() {
+                            diesel::insert_into(payment_items::table)
+                                .values(&items)
+                                .on_conflict_do_nothing()
+                                .execute(tx)
+                                .await
+                                .context("Failed to create payment items")?;
+                        }
+
+                        payments::table
+                            .find(payload.payment_id)
+                            .get_result::<PaymentEntity>(tx)
+                            .await
+                            .context("Failed to get payment")
+                    })
+                })
+                .await?;
+
+            // Charged by an earlier delivery, or voided before it could be
+            if payment.provider_ref.is_some() || payment.status != "PENDING" {
+                info!(
+                    status = payment.status,
+                    "Payment was already handled, skipping"
+                );
+                return Ok(());
+            }
+
+            let provider = state.providers.get(&payment.provider).ok_or_else(|| {
+                Poison(anyhow!(
+                    "Payment provider \"{}\" is not enabled",
+                    payment.provider
+                ))
+            })?;
+            let charge = provider.create_charge(&payment).await?;
+
+            conn.transaction(|tx| {
+                let event = &event;
+                Box::pin(async move {
+                    if !inbox::record(tx, event).await? {
+                        return Ok(());
+                    }
+
+                    // Kept even if the payment was voided meanwhile, so webhooks still find it
+                    diesel::update(payments::table.find(payment.id))
+                        .set(payments::provider_ref.eq(&charge.provider_ref))
+                        .execute(tx)
+                        .await
+                        .context("Failed to update payment")?;
+
+                    info!(
+                        amount = payment.amount,
+                        provider = payment.provider,
+                        provider_ref = charge.provider_ref,
+                        "Payment has been created"
+                    );
+
+                    // Some providers settle a charge as soon as it is created; both
+                    // only touch PENDING payments
+                    match charge.status {
+                        ChargeStatus::Pending => {}
+                        ChargeStatus::Succeeded => {
+                            payment_success::succeed(tx, payment.id).await?;
+                        }
+                        ChargeStatus::Failed { reason } => {
+                            payment_failure::fail(tx, payment.id, &reason).await?;
+                        }
+                    }
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .await?;
+
+            Ok(())
+        }
+        .instrument(span),
+    )
+}
+
+/// Voids the pending payment of a cancelled order, so it can no longer be paid.
+pub fn void_request(
+    event: EventEnvelope<PaymentVoidRequestedEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!(
+        "void_request",
+        order_id = event.payload.order_id,
+        payment_id = %event.payload.payment_id,
+    );
+    Box::pin(
+        async move {
+            let conn = &mut state
+                .db_pool
+                .get()
+                .await
+                .context("Failed to obtain a DB connection pool")?;
+
+            conn.transaction(|tx| {
+                let event = &event;
+                Box::pin(async move {
+                    if !inbox::record(tx, event).await? {
+                        return Ok(());
+                    }
+
+                    let payment_id = event.payload.payment_id;
+
+                    let status: Option<String> = payments::table
+                        .find(payment_id)
+                        .select(payments::status)
+                        .for_update()
+                        .get_result(tx)
+                        .await
+                        .optional()?;
+
+                    match status.as_deref() {
+                        // The pay request may not have been consumed yet: retry until it is
+                        None => {
+                            return Err(anyhow!("Payment {} does not exist yet", payment_id));
+                        }
+                        Some("PENDING") => {}
+                        // Nothing left to void, e.g. it expired before the order was cancelled
+                        Some("VOIDED" | "FAILED") => return Ok(()),
+                        // Paid before the void landed: the orders service asks for a refund
+                        // once it hears of the success
+                        Some("SUCCESS" | "PARTIALLY_REFUNDED" | "REFUNDED") => {
+                            info!(
+                                status,
+                                "Payment was already paid, leaving it to be refunded"
+                            );
+                            return Ok(());
+                        }
+                        Some(status) => {
+                            return Err(Poison(anyhow!(
+                                "Payment {} is {} and cannot be voided",
+                                payment_id,
+                                status
+                            ))
+                            .into());
+                        }
+                    }
+
+                    diesel::update(payments::table.find(payment_id))
+                        .set(payments::status.eq("VOIDED"))
+                        .execute(tx)
+                        .await
+                        .context("Failed to void payment")?;
+
+                    info!("Payment has been voided");
+                    Ok::<_, anyhow::Error>(())
+                })
             })
-            .returning(PaymentEntity::as_returning())
-            .get_result(conn)
-            .await
-            .context("Failed to create payment")?;
+            .await?;
+
+            Ok(())
+        }
+        .instrument(span),
+    )
+}
+
+/// Refunds the whole payment of an order that was paid for after it was
+/// cancelled or expired.
+pub fn refund_request(
+    event: EventEnvelope<PaymentRefundRequestedEvent>,
+    state: AppState,
+) -> BoxFuture<'static, Result<()>> {
+    let span = info_span!(
+        "refund_request",
+        order_id = event.payload.order_id,
+        payment_id = %event.payload.payment_id,
+    );
+    Box::pin(
+        async move {
+            let conn = &mut state
+                .db_pool
+                .get()
+                .await
+                .context("Failed to obtain a DB connection pool")?;
+
+            let refund = conn
+                .transaction(|tx| {
+                    let event = &event;
+                    Box::pin(async move {
+                        if !inbox::record(tx, event).await? {
+                            return Ok(None);
+                        }
+
+                        let request = RefundRequest {
+                            reason: Some(event.payload.reason.clone()),
+                            ..Default::default()
+                        };
+
+                        let refund =
+                            match refunds::begin(tx, event.payload.payment_id, request).await {
+                                Ok(refund) => Some(refund),
+                                // Already refunded in full, e.g. by the pharmacy
+                                Err(AppError::NotRefundable(_, status)) if status == "REFUNDED" => {
+                                    info!("Payment was already refunded");
+                                    None
+                                }
+                                Err(e @ AppError::Base(_)) => return Err(e.into()),
+                                Err(e) => return Err(Poison(e.into()).into()),
+                            };
+
+                        Ok::<_, anyhow::Error>(refund)
+                    })
+                })
+                .await?;
 
-        info!("Payment #{} has been created", payment.id);
+            let Some(refund) = refund else {
+                return Ok(());
+            };
 
-        delivery.ack(BasicAckOptions::default()).await?;
+            // Recorded already, so the retry job settles it if the provider is unreachable
+            match refunds::settle(conn, &state.providers, refund.id).await {
+                Ok(refund) => info!(
+                    refund_id = %refund.id,
+                    status = refund.status,
+                    "Refund of the closed order's payment has been settled"
+                ),
+                Err(e) => warn!(
+                    refund_id = %refund.id,
+                    error = format!("{:#}", e),
+                    "Failed to settle refund, leaving it to the retry job"
+                ),
+            }
 
-        Ok(())
-    })
+            Ok(())
+        }
+        .instrument(span),
+    )
 }
@@ -0,0 +1,2 @@
+pub mod payment_expiry;
+pub mod refund_retry;
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use medbook_common::config::env_or;
+use tracing::{Instrument, error, info, info_span};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    models::PaymentEntity,
+    payment_failure, payment_success,
+    providers::{ChargeStatus, ProviderRegistry},
+    schema::payments,
+};
+
+const EXPIRED_REASON: &str = "Payment was not completed in time";
+
+#[derive(Clone, Debug)]
+pub struct PaymentExpiryConfig {
+    /// How long a payment may stay PENDING before it fails.
+    pub ttl: Duration,
+    /// How often the job looks for expired payments.
+    pub interval: Duration,
+    /// Maximum number of payments expired per run.
+    pub batch_size: i64,
+}
+
+impl PaymentExpiryConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            ttl: Duration::from_secs(env_or("PAYMENT_EXPIRY_TTL_SECS", 15 * 60)?),
+            interval: Duration::from_secs(env_or("PAYMENT_EXPIRY_INTERVAL_SECS", 60)?),
+            batch_size: env_or("PAYMENT_EXPIRY_BATCH_SIZE", 100)?,
+        })
+    }
+}
+
+/// Periodically fails the payments that were not completed within the TTL, so
+/// their orders do not wait in PAYMENT_PROCESSING forever. Their provider is
+/// asked first, in case a confirmation or a decline got lost.
+pub fn init(state: AppState, config: PaymentExpiryConfig) {
+    info!("Payment expiry job initialized");
+    let shutdown = state.shutdown.clone();
+
+    shutdown.clone().spawn(async move {
+        while !shutdown.is_cancelled() {
+            if let Err(e) = run(&state, &config).await {
+                error!(error = format!("{:#}", e), "Payment expiry job failed");
+            }
+            shutdown.sleep(config.interval).await;
+        }
+    });
+}
+
+async fn run(state: &AppState, config: &PaymentExpiryConfig) -> anyhow::Result<()> {
+    let cutoff = Utc::now() - config.ttl;
+    let conn = &mut state.db_pool.get().await?;
+
+    let payment_ids: Vec<Uuid> = payments::table
+        .filter(payments::status.eq("PENDING"))
+        .filter(payments::created_at.lt(cutoff))
+        .order(payments::created_at.asc())
+        .select(payments::id)
+        .limit(config.batch_size)
+        .load(conn)
+        .await?;
+
+    for payment_id in payment_ids {
+        let span = info_span!("expire_payment", payment_id = %payment_id);
+        if let Err(e) = expire(conn, &state.providers, payment_id)
+            .instrument(span)
+            .await
+        {
+            error!(error = format!("{:#}", e), "Failed to expire payment");
+        }
+    }
+
+    Ok(())
+}
+
+async fn expire(
+    conn: &mut AsyncPgConnection,
+    providers: &ProviderRegistry,
+    payment_id: Uuid,
+) -> anyhow::Result<()> {
+    let payment: PaymentEntity = payments::table.find(payment_id).get_result(conn).await?;
+
+    // The provider may have settled the charge without us hearing about it. Asked
+    // before the payment is locked, so no lock is held while it answers
+    let status = match (providers.get(&payment.provider), &payment.provider_ref) {
+        (Some(provider), Some(provider_ref)) => provider.query_status(provider_ref).await?,
+        _ => ChargeStatus::Pending,
+    };
+
+    conn.transaction(|conn| {
+        Box::pin(async move {
+            // One paid in the meantime is left alone
+            let Some(payment): Option<PaymentEntity> = payments::table
+                .find(payment_id)
+                .filter(payments::status.eq("PENDING"))
+                .for_update()
+                .get_result(conn)
+                .await
+                .optional()?
+            else {
+                return Ok(());
+            };
+
+            match status {
+                ChargeStatus::Succeeded => {
+                    payment_success::succeed(conn, payment_id).await?;
+                    info!(
+                        order_id = payment.order_id,
+                        "Payment had succeeded after all"
+                    );
+                }
+                ChargeStatus::Failed { reason } => {
+                    payment_failure::fail(conn, payment_id, &reason).await?;
+                    info!(order_id = payment.order_id, reason, "Payment had failed");
+                }
+                ChargeStatus::Pending => {
+                    payment_failure::fail(conn, payment_id, EXPIRED_REASON).await?;
+                    info!(order_id = payment.order_id, "Payment has expired");
+                }
+            }
+
+            Ok::<_, anyhow::Error>(())
+        })
+    })
+    .await
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use medbook_common::config::env_or;
+use tracing::{Instrument, error, info, info_span};
+use uuid::Uuid;
+
+use crate::{app_state::AppState, refunds, schema::refunds as refunds_table};
+
+#[derive(Clone, Debug)]
+pub struct RefundRetryConfig {
+    /// How long a refund may stay PENDING before the job settles it, so it does
+    /// not race the request that created it.
+    pub grace: Duration,
+    /// How often the job looks for pending refunds.
+    pub interval: Duration,
+    /// Maximum number of refunds settled per run.
+    pub batch_size: i64,
+}
+
+impl RefundRetryConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            grace: Duration::from_secs(env_or("REFUND_RETRY_GRACE_SECS", 60)?),
+            interval: Duration::from_secs(env_or("REFUND_RETRY_INTERVAL_SECS", 60)?),
+            batch_size: env_or("REFUND_RETRY_BATCH_SIZE", 100)?,
+        })
+    }
+}
+
+/// Periodically settles the refunds left PENDING, e.g. because the service went
+/// down between recording one and hearing back from the provider.
+pub fn init(state: AppState, config: RefundRetryConfig) {
+    info!("Refund retry job initialized");
+    let shutdown = state.shutdown.clone();
+
+    shutdown.clone().spawn(async move {
+        while !shutdown.is_cancelled() {
+            if let Err(e) = run(&state, &config).await {
+                error!(error = format!("{:#}", e), "Refund retry job failed");
+            }
+            shutdown.sleep(config.interval).await;
+        }
+    });
+}
+
+async fn run(state: &AppState, config: &RefundRetryConfig) -> anyhow::Result<()> {
+    let cutoff = Utc::now() - config.grace;
+    let conn = &mut state.db_pool.get().await?;
+
+    let refund_ids: Vec<Uuid> = refunds_table::table
+        .filter(refunds_table::status.eq("PENDING"))
+        .filter(refunds_table::created_at.lt(cutoff))
+        .order(refunds_table::created_at.asc())
+        .select(refunds_table::id)
+        .limit(config.batch_size)
+        .load(conn)
+        .await?;
+
+    for refund_id in refund_ids {
+        let span = info_span!("retry_refund", refund_id = %refund_id);
+        match refunds::settle(conn, &state.providers, refund_id)
+            .instrument(span)
+            .await
+        {
+            Ok(refund) => {
+                info!(refund_id = %refund_id, status = refund.status, "Refund has been settled")
+            }
+            Err(e) => error!(
+                refund_id = %refund_id,
+                error = format!("{:#}", e),
+                "Failed to settle refund"
+            ),
+        }
+    }
+
+    Ok(())
+}
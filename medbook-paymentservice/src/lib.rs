@@ -1,8 +1,15 @@
 pub mod app_error;
 pub mod app_state;
 pub mod consumers;
-pub mod db;
+pub mod jobs;
 pub mod models;
-pub mod outbox;
+pub mod payment_failure;
+pub mod payment_success;
+pub mod promptpay;
+pub mod providers;
+pub mod refunds;
 pub mod routes;
 pub mod schema;
+
+/// Name stamped as `producer` on every event this service emits.
+pub const SERVICE_NAME: &str = "medbook-paymentservice";
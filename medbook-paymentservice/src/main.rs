@@ -1,24 +1,51 @@
-use medbook_paymentservice::{app_state, consumers, outbox, routes};
+use medbook_common::{
+    consumers::ConsumerConfig,
+    dead_letters, health,
+    outbox::{self, OutboxConfig},
+    prometheus, shutdown, telemetry,
+};
+use medbook_events::{
+    OrderPayRequestEvent, PaymentRefundRequestedEvent, PaymentVoidRequestedEvent,
+};
+use medbook_paymentservice::{
+    SERVICE_NAME, app_state, consumers,
+    jobs::{
+        payment_expiry::{self, PaymentExpiryConfig},
+        refund_retry::{self, RefundRetryConfig},
+    },
+    routes,
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
-        .init();
-
     dotenvy::dotenv().ok();
+    telemetry::init(SERVICE_NAME)?;
     tracing::info!(".env files loaded");
 
     let ip = format!("0.0.0.0:{}", std::env::var("PORT")?);
     tracing::info!("Starting PaymentService on {}...", ip);
 
+    prometheus::init()?;
     let app_state = app_state::AppState::init().await?;
+    let consumer_config = ConsumerConfig::from_env()?;
 
-    consumers::init(
-        "payments.pay_request".into(),
+    medbook_common::consumers::consume::<OrderPayRequestEvent, _>(
         consumers::payments::pay_request,
         app_state.clone(),
-    );
+        consumer_config.clone(),
+    )?;
+
+    medbook_common::consumers::consume::<PaymentVoidRequestedEvent, _>(
+        consumers::payments::void_request,
+        app_state.clone(),
+        consumer_config.clone(),
+    )?;
+
+    medbook_common::consumers::consume::<PaymentRefundRequestedEvent, _>(
+        consumers::payments::refund_request,
+        app_state.clone(),
+        consumer_config.clone(),
+    )?;
 
     // consumers::init(
     //     "orders.order_rejected".into(),
@@ -26,15 +53,27 @@ async fn main() -> anyhow::Result<()> {
     //     app_state.clone(),
     // );
 
-    outbox::init(app_state.clone());
+    outbox::init(app_state.clone(), OutboxConfig::from_env()?);
+    payment_expiry::init(app_state.clone(), PaymentExpiryConfig::from_env()?);
+    refund_retry::init(app_state.clone(), RefundRetryConfig::from_env()?);
 
     let app = axum::Router::new()
-        .nest("/payments", routes::payments::routes())
+        .nest("/payments", routes::payments::routes()?)
+        .nest("/admin/dead-letters", dead_letters::routes()?)
+        .nest("/health", health::routes())
         .route("/health-check", axum::routing::get(|| async { "OK" }))
-        .with_state(app_state);
+        .nest("/metrics", prometheus::routes())
+        .layer(axum::middleware::from_fn(prometheus::track_http))
+        .layer(axum::middleware::from_fn(telemetry::trace_http))
+        .layer(axum::middleware::from_fn(telemetry::request_id))
+        .with_state(app_state.clone());
 
     let listener = tokio::net::TcpListener::bind(ip).await.unwrap();
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(app_state.shutdown.clone().signal())
+        .await?;
+
+    shutdown::drain(app_state).await?;
 
     Ok(())
 }
@@ -19,6 +19,9 @@ pub struct PaymentEntity {
     pub failure_reason: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub correlation_id: Uuid,
+    /// When the provider took the money after the payment was voided or failed.
+    pub captured_late_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Insertable, Serialize, Deserialize, Debug)]
@@ -30,22 +33,58 @@ pub struct CreatePaymentEntity {
     pub amount: f32,
     pub provider: String,
     pub status: String,
+    pub correlation_id: Uuid,
 }
 
-#[derive(Queryable, Selectable, Debug)]
-#[diesel(table_name = crate::schema::outbox)]
+#[derive(Queryable, Serialize, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::schema::refunds)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct OutboxEntity {
-    pub id: i32,
-    pub event_type: String,
-    pub payload: String,
+pub struct RefundEntity {
+    pub id: Uuid,
+    pub payment_id: Uuid,
+    pub reason: Option<String>,
     pub status: String,
+    pub provider_ref: Option<String>,
+    pub failure_reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// In satang.
+    pub amount_minor: i64,
+}
+
+/// Created PENDING, settled once the provider answered.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::refunds)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreateRefundEntity {
+    pub payment_id: Uuid,
+    pub amount_minor: i64,
+    pub reason: Option<String>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::payment_items)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreatePaymentItemEntity {
+    pub payment_id: Uuid,
+    pub product_id: i32,
+    pub quantity: i32,
 }
 
-#[derive(Queryable, Insertable, Selectable, Debug)]
-#[diesel(table_name = crate::schema::outbox)]
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::refund_items)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
-pub struct CreateOutboxEntity {
-    pub event_type: String,
-    pub payload: String,
+pub struct CreateRefundItemEntity {
+    pub refund_id: Uuid,
+    pub product_id: i32,
+    pub quantity: i32,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::webhook_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreateWebhookEventEntity {
+    pub provider: String,
+    pub event_id: String,
+    pub payment_id: Option<Uuid>,
 }
@@ -0,0 +1,48 @@
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use medbook_common::outbox;
+use medbook_events::{EventEnvelope, PaymentFailedEvent};
+use uuid::Uuid;
+
+use crate::{SERVICE_NAME, models::PaymentEntity, schema::payments};
+
+/// Marks payment `payment_id` as FAILED because of `reason` and tells the orders
+/// service, provided it is still PENDING. Returns `None` when it is not.
+pub async fn fail(
+    conn: &mut AsyncPgConnection,
+    payment_id: Uuid,
+    reason: &str,
+) -> anyhow::Result<Option<PaymentEntity>> {
+    let Some(payment): Option<PaymentEntity> = diesel::update(
+        payments::table
+            .filter(payments::id.eq(payment_id))
+            .filter(payments::status.eq("PENDING")),
+    )
+    .set((
+        payments::status.eq("FAILED"),
+        payments::failure_reason.eq(reason),
+    ))
+    .returning(PaymentEntity::as_returning())
+    .get_result(conn)
+    .await
+    .optional()?
+    else {
+        return Ok(None);
+    };
+
+    let envelope = EventEnvelope::new(
+        SERVICE_NAME,
+        PaymentFailedEvent {
+            payment_id: payment.id,
+            order_id: payment.order_id,
+            reason: reason.into(),
+        },
+    )
+    .with_correlation_id(payment.correlation_id);
+
+    outbox::publish(conn, &envelope).await?;
+
+    metrics::counter!("payments_failed_total").increment(1);
+
+    Ok(Some(payment))
+}
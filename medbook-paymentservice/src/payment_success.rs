@@ -0,0 +1,43 @@
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use medbook_common::outbox;
+use medbook_events::{EventEnvelope, OrderPaymentSuccessEvent};
+use uuid::Uuid;
+
+use crate::{SERVICE_NAME, models::PaymentEntity, schema::payments};
+
+/// Marks payment `payment_id` as SUCCESS and tells the orders service, provided it
+/// is still PENDING. Returns `None` when it is not.
+pub async fn succeed(
+    conn: &mut AsyncPgConnection,
+    payment_id: Uuid,
+) -> anyhow::Result<Option<PaymentEntity>> {
+    let Some(payment): Option<PaymentEntity> = diesel::update(
+        payments::table
+            .filter(payments::id.eq(payment_id))
+            .filter(payments::status.eq("PENDING")),
+    )
+    .set(payments::status.eq("SUCCESS"))
+    .returning(PaymentEntity::as_returning())
+    .get_result(conn)
+    .await
+    .optional()?
+    else {
+        return Ok(None);
+    };
+
+    let envelope = EventEnvelope::new(
+        SERVICE_NAME,
+        OrderPaymentSuccessEvent {
+            payment_id: payment.id,
+            order_id: payment.order_id,
+            amount: payment.amount,
+            provider: payment.provider.clone(),
+        },
+    )
+    .with_correlation_id(payment.correlation_id);
+
+    outbox::publish(conn, &envelope).await?;
+
+    Ok(Some(payment))
+}
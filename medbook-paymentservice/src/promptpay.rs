@@ -0,0 +1,200 @@
+use anyhow::{Context, bail};
+use qrcode::{QrCode, render::svg};
+
+/// Application id of PromptPay credit transfers, EMVCo tag 29 sub-tag 00.
+const PROMPTPAY_AID: &str = "A000000677010111";
+
+/// Who PromptPay transfers are paid to: a mobile number, a national or tax id, or
+/// an e-wallet id.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyId {
+    /// `0066` followed by the number without its leading `0`, 13 digits.
+    Mobile(String),
+    /// 13 digits.
+    TaxId(String),
+    /// 15 digits.
+    EWallet(String),
+}
+
+impl ProxyId {
+    /// Parses a proxy id as people write it, e.g. `081-234-5678`.
+    pub fn parse(id: &str) -> anyhow::Result<Self> {
+        let digits: String = id.chars().filter(|c| !matches!(c, '-' | ' ')).collect();
+        if !digits.chars().all(|c| c.is_ascii_digit()) {
+            bail!("PromptPay id \"{}\" must only contain digits", id);
+        }
+
+        match digits.len() {
+            10 if digits.starts_with('0') => Ok(ProxyId::Mobile(format!("0066{}", &digits[1..]))),
+            13 => Ok(ProxyId::TaxId(digits)),
+            15 => Ok(ProxyId::EWallet(digits)),
+            _ => bail!(
+                "PromptPay id \"{}\" is neither a mobile number, a tax id nor an e-wallet id",
+                id
+            ),
+        }
+    }
+
+    fn tag(&self) -> (&'static str, &str) {
+        match self {
+            ProxyId::Mobile(id) => ("01", id),
+            ProxyId::TaxId(id) => ("02", id),
+            ProxyId::EWallet(id) => ("03", id),
+        }
+    }
+}
+
+/// The PromptPay merchant payments are made out to.
+#[derive(Debug, Clone)]
+pub struct PromptPayConfig {
+    pub proxy_id: ProxyId,
+}
+
+impl PromptPayConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let id = std::env::var("PROMPTPAY_ID").context("PROMPTPAY_ID is invalid")?;
+        Ok(Self {
+            proxy_id: ProxyId::parse(&id)?,
+        })
+    }
+}
+
+/// Builds the EMVCo payload of a one-time PromptPay QR code for `amount` baht.
+pub fn payload(proxy_id: &ProxyId, amount: f32) -> anyhow::Result<String> {
+    // A code without a usable amount would let the patient pick one
+    if !amount.is_finite() || amount <= 0.0 {
+        bail!("PromptPay amount {} must be a positive number", amount);
+    }
+    let amount = format!("{:.2}", amount);
+    if amount.len() > 13 {
+        bail!("PromptPay amount {} is too large", amount);
+    }
+
+    let (proxy_tag, proxy) = proxy_id.tag();
+    let merchant = [field("00", PROMPTPAY_AID), field(proxy_tag, proxy)].concat();
+
+    let mut payload = [
+        // Payload format indicator
+        field("00", "01"),
+        // Point of initiation: dynamic, the code is only good for this payment
+        field("01", "12"),
+        field("29", &merchant),
+        // Transaction currency, ISO 4217 numeric code of THB
+        field("53", "764"),
+        field("54", &amount),
+        field("58", "TH"),
+    ]
+    .concat();
+
+    // The checksum covers its own tag and length
+    payload.push_str("6304");
+    let crc = crc16(payload.as_bytes());
+    payload.push_str(&format!("{:04X}", crc));
+    Ok(payload)
+}
+
+/// Renders `payload` as an SVG image.
+pub fn svg(payload: &str) -> anyhow::Result<String> {
+    let code = QrCode::new(payload.as_bytes()).context("Failed to encode QR code")?;
+    Ok(code.render::<svg::Color>().min_dimensions(256, 256).build())
+}
+
+fn field(tag: &str, value: &str) -> String {
+    format!("{}{:02}{}", tag, value.len(), value)
+}
+
+/// CRC-16/CCITT-FALSE, as required by EMVCo.
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_matches_the_standard_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn crc16_matches_a_known_promptpay_code() {
+        // Static code of mobile number 000-000-0000, ending with its CRC
+        let code = "00020101021129370016A000000677010111011300660000000005802TH530376463048956";
+        let (body, crc) = code.split_at(code.len() - 4);
+        assert_eq!(format!("{:04X}", crc16(body.as_bytes())), crc);
+    }
+
+    #[test]
+    fn builds_a_dynamic_payload_with_the_amount() {
+        let proxy_id = ProxyId::parse("081-234-5678").unwrap();
+        let payload = payload(&proxy_id, 4.22).unwrap();
+
+        assert_eq!(
+            &payload[..payload.len() - 4],
+            concat!(
+                "000201",
+                "010212",
+                "29370016A000000677010111",
+                "01130066812345678",
+                "5303764",
+                "54044.22",
+                "5802TH",
+                "6304",
+            )
+        );
+        let (body, crc) = payload.split_at(payload.len() - 4);
+        assert_eq!(format!("{:04X}", crc16(body.as_bytes())), crc);
+    }
+
+    #[test]
+    fn rejects_unusable_amounts() {
+        let proxy_id = ProxyId::parse("0812345678").unwrap();
+        for amount in [f32::NAN, f32::INFINITY, -1.0, 0.0, 1e13] {
+            assert!(payload(&proxy_id, amount).is_err(), "{}", amount);
+        }
+    }
+
+    #[test]
+    fn parses_mobile_numbers() {
+        for id in ["0812345678", "081-234-5678", "081 234 5678"] {
+            assert_eq!(
+                ProxyId::parse(id).unwrap(),
+                ProxyId::Mobile("0066812345678".into())
+            );
+        }
+    }
+
+    #[test]
+    fn parses_tax_and_e_wallet_ids() {
+        assert_eq!(
+            ProxyId::parse("1-2345-67890-12-1").unwrap(),
+            ProxyId::TaxId("1234567890121".into())
+        );
+        assert_eq!(
+            ProxyId::parse("123456789012345").unwrap(),
+            ProxyId::EWallet("123456789012345".into())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        for id in [
+            "",
+            "081234567",
+            "1812345678",
+            "08123456ab",
+            "12345678901234",
+        ] {
+            assert!(ProxyId::parse(id).is_err(), "{}", id);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::Context;
+use axum::http::HeaderMap;
+use futures::future::BoxFuture;
+
+use crate::{
+    models::{PaymentEntity, RefundEntity},
+    promptpay::{self, PromptPayConfig},
+    providers::{
+        Charge, ChargeStatus, PaymentProvider, ProviderRefund, WebhookEvent,
+        signature::WebhookSecret,
+    },
+};
+
+pub const NAME: &str = "qr_payment";
+
+/// Stands in for a real provider. Charges come with a PromptPay QR code and are
+/// settled by signed webhooks, or through `POST /payments/{id}/mock-pay` and
+/// `/mock-fail` outside production. Refunds always go through.
+pub struct MockProvider {
+    promptpay: PromptPayConfig,
+    webhook_secret: WebhookSecret,
+}
+
+impl MockProvider {
+    pub fn new(promptpay: PromptPayConfig, webhook_secret: WebhookSecret) -> Self {
+        Self {
+            promptpay,
+            webhook_secret,
+        }
+    }
+}
+
+impl PaymentProvider for MockProvider {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn create_charge<'a>(
+        &'a self,
+        payment: &'a PaymentEntity,
+    ) -> BoxFuture<'a, anyhow::Result<Charge>> {
+        Box::pin(async move {
+            Ok(Charge {
+                provider_ref: format!("mock_ch_{}", payment.id.simple()),
+                status: ChargeStatus::Pending,
+            })
+        })
+    }
+
+    fn query_status<'a>(
+        &'a self,
+        _provider_ref: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<ChargeStatus>> {
+        Box::pin(async move { Ok(ChargeStatus::Pending) })
+    }
+
+    fn refund<'a>(
+        &'a self,
+        _payment: &'a PaymentEntity,
+        refund: &'a RefundEntity,
+    ) -> BoxFuture<'a, anyhow::Result<ProviderRefund>> {
+        Box::pin(async move {
+            Ok(ProviderRefund {
+                provider_ref: format!("mock_re_{}", refund.id.simple()),
+            })
+        })
+    }
+
+    fn verify_webhook(&self, headers: &HeaderMap, body: &[u8]) -> anyhow::Result<WebhookEvent> {
+        self.webhook_secret.verify(headers, body)?;
+        serde_json::from_slice(body).context("Invalid webhook payload")
+    }
+
+    fn qr_payload(&self, payment: &PaymentEntity) -> anyhow::Result<Option<String>> {
+        promptpay::payload(&self.promptpay.proxy_id, payment.amount).map(Some)
+    }
+}
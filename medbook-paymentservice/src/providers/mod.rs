@@ -0,0 +1,148 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, bail};
+use axum::http::HeaderMap;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    models::{PaymentEntity, RefundEntity},
+    promptpay::PromptPayConfig,
+    providers::signature::WebhookSecret,
+};
+
+pub mod mock;
+pub mod signature;
+
+/// Where a charge stands on the provider's side.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ChargeStatus {
+    Pending,
+    Succeeded,
+    Failed { reason: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct Charge {
+    /// The provider's id for the charge, stored as the payment's `provider_ref`.
+    pub provider_ref: String,
+    pub status: ChargeStatus,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProviderRefund {
+    /// The provider's id for the refund, stored as the refund's `provider_ref`.
+    pub provider_ref: String,
+}
+
+/// A webhook whose signature checked out.
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebhookEvent {
+    /// The provider's id for the notification, to recognise redeliveries.
+    pub id: String,
+    /// The charge the notification is about.
+    pub provider_ref: String,
+    #[serde(flatten)]
+    pub status: ChargeStatus,
+}
+
+/// A payment provider the patients can pay with, e.g. a PSP or a bank.
+///
+/// Calls are made outside of any DB transaction and retried when what follows
+/// them fails, so implementations pass an idempotency key along where the
+/// provider supports one: the payment id for `create_charge`, the refund id for
+/// `refund`, as a payment may be refunded several times.
+pub trait PaymentProvider: Send + Sync {
+    /// The name orders refer to the provider by, stored as the payment's `provider`.
+    fn name(&self) -> &'static str;
+
+    fn create_charge<'a>(
+        &'a self,
+        payment: &'a PaymentEntity,
+    ) -> BoxFuture<'a, anyhow::Result<Charge>>;
+
+    fn query_status<'a>(
+        &'a self,
+        provider_ref: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<ChargeStatus>>;
+
+    /// Gives `refund.amount_minor` satang of `payment` back. An error means
+    /// nothing was refunded.
+    fn refund<'a>(
+        &'a self,
+        payment: &'a PaymentEntity,
+        refund: &'a RefundEntity,
+    ) -> BoxFuture<'a, anyhow::Result<ProviderRefund>>;
+
+    /// Checks that a webhook really comes from the provider and parses it.
+    fn verify_webhook(&self, headers: &HeaderMap, body: &[u8]) -> anyhow::Result<WebhookEvent>;
+
+    /// The payload of a QR code patients scan to pay `payment`, for providers
+    /// paid that way.
+    fn qr_payload(&self, _payment: &PaymentEntity) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProviderInfo {
+    pub name: &'static str,
+}
+
+/// The providers enabled with `PAYMENT_PROVIDERS`, by name.
+#[derive(Clone)]
+pub struct ProviderRegistry {
+    providers: HashMap<&'static str, Arc<dyn PaymentProvider>>,
+}
+
+impl ProviderRegistry {
+    /// Enables the comma separated providers of `PAYMENT_PROVIDERS`, only the mock
+    /// `qr_payment` one by default.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let enabled = std::env::var("PAYMENT_PROVIDERS").unwrap_or_else(|_| mock::NAME.to_string());
+
+        let mut providers: HashMap<&'static str, Arc<dyn PaymentProvider>> = HashMap::new();
+        for name in enabled.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let provider: Arc<dyn PaymentProvider> = match name {
+                mock::NAME => Arc::new(mock::MockProvider::new(
+                    PromptPayConfig::from_env()?,
+                    WebhookSecret::from_env(mock::NAME)?,
+                )),
+                _ => bail!("Unknown payment provider \"{}\" in PAYMENT_PROVIDERS", name),
+            };
+            providers.insert(provider.name(), provider);
+        }
+
+        if providers.is_empty() {
+            bail!("PAYMENT_PROVIDERS does not enable any payment provider");
+        }
+
+        Ok(Self { providers })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn PaymentProvider>> {
+        self.providers.get(name)
+    }
+
+    /// Like [`ProviderRegistry::get`], for payments made with a provider that must
+    /// still be enabled.
+    pub fn for_payment(
+        &self,
+        payment: &PaymentEntity,
+    ) -> anyhow::Result<&Arc<dyn PaymentProvider>> {
+        self.get(&payment.provider)
+            .with_context(|| format!("Payment provider \"{}\" is not enabled", payment.provider))
+    }
+
+    /// The enabled providers, sorted by name.
+    pub fn list(&self) -> Vec<ProviderInfo> {
+        let mut providers: Vec<_> = self
+            .providers
+            .keys()
+            .map(|&name| ProviderInfo { name })
+            .collect();
+        providers.sort_by_key(|p| p.name);
+        providers
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use anyhow::{Context, anyhow, bail};
+use axum::http::HeaderMap;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use medbook_common::{config::env_or, stage::Stage};
+use sha2::Sha256;
+
+/// Unix time, in seconds, at which the webhook was signed.
+pub const TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+/// Hex encoded HMAC-SHA256 of `{timestamp}.{body}`.
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+/// How the secrets of `.env.example` start, only accepted in Local and Development.
+const PLACEHOLDER: &str = "change-me";
+
+/// How the webhooks of a provider are signed.
+#[derive(Clone, Debug)]
+pub struct WebhookSecret {
+    secret: String,
+    /// How far the timestamp may be from now, so captured webhooks cannot be
+    /// replayed later.
+    tolerance: Duration,
+}
+
+impl WebhookSecret {
+    /// Reads the secret of `provider` from `{PROVIDER}_WEBHOOK_SECRET`.
+    pub fn from_env(provider: &str) -> anyhow::Result<Self> {
+        let key = format!("{}_WEBHOOK_SECRET", provider.to_uppercase());
+        let secret = std::env::var(&key).with_context(|| format!("{} is invalid", key))?;
+        if secret.len() < 16 {
+            bail!("{} must be at least 16 bytes long", key);
+        }
+        if secret.starts_with(PLACEHOLDER)
+            && !matches!(Stage::from_env()?, Stage::Local | Stage::Development)
+        {
+            bail!("{} is still the placeholder of .env.example", key);
+        }
+
+        Ok(Self {
+            secret,
+            tolerance: Duration::from_secs(env_or("WEBHOOK_TOLERANCE_SECS", 5 * 60)?),
+        })
+    }
+
+    /// Checks that `body` was signed with the secret, recently enough.
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8]) -> anyhow::Result<()> {
+        let timestamp = header(headers, TIMESTAMP_HEADER)?;
+        let signed_at: i64 = timestamp.parse().context("Invalid webhook timestamp")?;
+        if (Utc::now().timestamp() - signed_at).unsigned_abs() > self.tolerance.as_secs() {
+            bail!("Webhook timestamp {} is too far from now", signed_at);
+        }
+
+        let signature =
+            hex::decode(header(headers, SIGNATURE_HEADER)?).context("Invalid webhook signature")?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .context("Invalid webhook secret")?;
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+
+        // Compared in constant time
+        mac.verify_slice(&signature)
+            .map_err(|_| anyhow!("Webhook signature does not match"))
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> anyhow::Result<&'a str> {
+    headers
+        .get(name)
+        .with_context(|| format!("Missing {} header", name))?
+        .to_str()
+        .with_context(|| format!("Invalid {} header", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"id":"evt_1","provider_ref":"mock_ch_1","status":"SUCCEEDED"}"#;
+
+    fn secret() -> WebhookSecret {
+        WebhookSecret {
+            secret: "test-secret".into(),
+            tolerance: Duration::from_secs(300),
+        }
+    }
+
+    /// Headers of `body` signed by `secret` at `timestamp`.
+    fn signed(secret: &str, timestamp: i64, body: &[u8]) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(body);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, timestamp.to_string().parse().unwrap());
+        headers.insert(
+            SIGNATURE_HEADER,
+            hex::encode(mac.finalize().into_bytes()).parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn accepts_a_good_signature() {
+        let headers = signed("test-secret", Utc::now().timestamp(), BODY);
+        assert!(secret().verify(&headers, BODY).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_tampered_body() {
+        let headers = signed("test-secret", Utc::now().timestamp(), BODY);
+        let tampered = String::from_utf8_lossy(BODY).replace("SUCCEEDED", "FAILED");
+        assert!(secret().verify(&headers, tampered.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn rejects_another_secret() {
+        let headers = signed("other-secret", Utc::now().timestamp(), BODY);
+        assert!(secret().verify(&headers, BODY).is_err());
+    }
+
+    #[test]
+    fn rejects_a_stale_or_future_timestamp() {
+        // Well outside the tolerance, so a slow test cannot bring it back within
+        let outside = secret().tolerance.as_secs() as i64 + 60;
+        for offset in [-outside, outside] {
+            let headers = signed("test-secret", Utc::now().timestamp() + offset, BODY);
+            assert!(secret().verify(&headers, BODY).is_err(), "{}", offset);
+        }
+    }
+
+    #[test]
+    fn rejects_missing_headers() {
+        assert!(secret().verify(&HeaderMap::new(), BODY).is_err());
+    }
+}
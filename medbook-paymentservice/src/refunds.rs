@@ -0,0 +1,468 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper};
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use medbook_common::{app_error::BaseError, outbox};
+use medbook_events::{EventEnvelope, OrderItem, PaymentRefundedEvent};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    SERVICE_NAME,
+    app_error::AppError,
+    models::{CreateRefundEntity, CreateRefundItemEntity, PaymentEntity, RefundEntity},
+    providers::ProviderRegistry,
+    schema::{payment_items, payments, refund_items, refunds},
+};
+
+/// Refunds that count against what is left to refund: the ones given back and
+/// the ones still waiting on the provider.
+const OUTSTANDING: [&str; 2] = ["PENDING", "SUCCEEDED"];
+
+/// Payments are stored in baht, refunds in satang.
+pub fn to_minor(amount: f32) -> i64 {
+    (f64::from(amount) * 100.0).round() as i64
+}
+
+#[derive(Debug, Default)]
+pub struct RefundRequest {
+    /// In satang, defaults to everything that has not been refunded yet.
+    pub amount_minor: Option<i64>,
+    pub reason: Option<String>,
+    /// Items the patient handed back, to be restocked.
+    pub returned_items: Vec<OrderItem>,
+}
+
+/// Records a PENDING refund of payment `payment_id`, once checked against what is
+/// left to refund and to return. The payment is locked until the end of the
+/// transaction `conn` is in, so concurrent refunds cannot exceed it together.
+///
+/// The provider is only asked once that transaction committed, see [`settle`].
+pub async fn begin(
+    conn: &mut AsyncPgConnection,
+    payment_id: Uuid,
+    request: RefundRequest,
+) -> Result<RefundEntity, AppError> {
+    let payment: PaymentEntity = payments::table
+        .find(payment_id)
+        .for_update()
+        .get_result(conn)
+        .await
+        .optional()
+        .context("Failed to get payment")?
+        .ok_or_else(|| BaseError::NotFound(format!("payments(id={})", payment_id)))?;
+
+    let refundable = match payment.status.as_str() {
+        "SUCCESS" | "PARTIALLY_REFUNDED" => true,
+        // Money taken after the payment was closed, see `routes::webhooks`
+        "VOIDED" | "FAILED" => payment.captured_late_at.is_some(),
+        _ => false,
+    };
+    if !refundable {
+        return Err(AppError::NotRefundable(payment_id, payment.status));
+    }
+
+    let refunded: Vec<i64> = refunds::table
+        .filter(refunds::payment_id.eq(payment_id))
+        .filter(refunds::status.eq_any(OUTSTANDING))
+        .select(refunds::amount_minor)
+        .load(conn)
+        .await
+        .context("Failed to get refunds")?;
+
+    let amount_minor = refund_amount(
+        request.amount_minor,
+        to_minor(payment.amount) - refunded.iter().sum::<i64>(),
+    )?;
+
+    let returned = returnable(conn, payment_id, &request.returned_items).await?;
+
+    let refund: RefundEntity = diesel::insert_into(refunds::table)
+        .values(CreateRefundEntity {
+            payment_id,
+            amount_minor,
+            reason: request.reason,
+        })
+        .returning(RefundEntity::as_returning())
+        .get_result(conn)
+        .await
+        .context("Failed to create refund")?;
+
+    if !returned.is_empty() {
+        let items: Vec<CreateRefundItemEntity> = returned
+            .into_iter()
+            .map(|(product_id, quantity)| CreateRefundItemEntity {
+                refund_id: refund.id,
+                product_id,
+                quantity,
+            })
+            .collect();
+
+        diesel::insert_into(refund_items::table)
+            .values(&items)
+            .execute(conn)
+            .await
+            .context("Failed to create refund items")?;
+    }
+
+    Ok(refund)
+}
+
+/// `requested`, or else all of `refundable`, once checked to be positive and
+/// within `refundable`.
+fn refund_amount(requested: Option<i64>, refundable: i64) -> Result<i64, AppError> {
+    let amount_minor = requested.unwrap_or(refundable);
+    if amount_minor <= 0 || amount_minor > refundable {
+        return Err(AppError::InvalidRefundAmount {
+            requested: amount_minor,
+            refundable,
+        });
+    }
+
+    Ok(amount_minor)
+}
+
+/// Merges `returned` by product and checks that, with what earlier refunds
+/// already took back, it does not exceed what the payment paid for.
+async fn returnable(
+    conn: &mut AsyncPgConnection,
+    payment_id: Uuid,
+    returned: &[OrderItem],
+) -> Result<BTreeMap<i32, i32>, AppError> {
+    let merged = merge(returned)?;
+    if merged.is_empty() {
+        return Ok(merged);
+    }
+
+    let paid: BTreeMap<i32, i32> = payment_items::table
+        .filter(payment_items::payment_id.eq(payment_id))
+        .select((payment_items::product_id, payment_items::quantity))
+        .load::<(i32, i32)>(conn)
+        .await
+        .context("Failed to get payment items")?
+        .into_iter()
+        .collect();
+
+    let mut already_returned: BTreeMap<i32, i32> = BTreeMap::new();
+    let earlier: Vec<(i32, i32)> = refund_items::table
+        .inner_join(refunds::table)
+        .filter(refunds::payment_id.eq(payment_id))
+        .filter(refunds::status.eq_any(OUTSTANDING))
+        .select((refund_items::product_id, refund_items::quantity))
+        .load(conn)
+        .await
+        .context("Failed to get returned items")?;
+    for (product_id, quantity) in earlier {
+        *already_returned.entry(product_id).or_default() += quantity;
+    }
+
+    check_returnable(&merged, &paid, &already_returned)?;
+    Ok(merged)
+}
+
+/// Quantities of `returned` by product, each checked to be positive.
+fn merge(returned: &[OrderItem]) -> Result<BTreeMap<i32, i32>, AppError> {
+    let mut merged: BTreeMap<i32, i32> = BTreeMap::new();
+    for item in returned {
+        if item.quantity <= 0 {
+            return Err(AppError::InvalidReturnedItems(format!(
+                "quantity of product {} must be positive",
+                item.product_id
+            )));
+        }
+        *merged.entry(item.product_id).or_default() += item.quantity;
+    }
+
+    Ok(merged)
+}
+
+/// Fails if a product of `returned` was not paid for, or not that many times
+/// once `already_returned` is taken off.
+fn check_returnable(
+    returned: &BTreeMap<i32, i32>,
+    paid: &BTreeMap<i32, i32>,
+    already_returned: &BTreeMap<i32, i32>,
+) -> Result<(), AppError> {
+    for (&product_id, &quantity) in returned {
+        let paid = paid.get(&product_id).copied().unwrap_or(0);
+        let left = paid - already_returned.get(&product_id).copied().unwrap_or(0);
+        if quantity > left {
+            return Err(AppError::InvalidReturnedItems(format!(
+                "{} of product {} returned, only {} left to return",
+                quantity, product_id, left
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks the provider to give PENDING refund `refund_id` back, then records the
+/// outcome: a refund that went through updates its payment and tells the orders
+/// service, unless the order never heard of the payment succeeding. Refunds
+/// already settled are returned as they are.
+///
+/// The provider is called outside of any transaction, with the refund id as its
+/// idempotency key, so a refund left PENDING by a crash can be settled again.
+pub async fn settle(
+    conn: &mut AsyncPgConnection,
+    providers: &ProviderRegistry,
+    refund_id: Uuid,
+) -> anyhow::Result<RefundEntity> {
+    let (refund, payment): (RefundEntity, PaymentEntity) = refunds::table
+        .inner_join(payments::table)
+        .filter(refunds::id.eq(refund_id))
+        .select((RefundEntity::as_select(), PaymentEntity::as_select()))
+        .get_result(conn)
+        .await
+        .context("Failed to get refund")?;
+
+    if refund.status != "PENDING" {
+        return Ok(refund);
+    }
+
+    let outcome = providers
+        .for_payment(&payment)?
+        .refund(&payment, &refund)
+        .await;
+
+    conn.transaction(|tx| {
+        Box::pin(async move {
+            // Settled concurrently, e.g. by the job while the provider was answering
+            let Some(refund): Option<RefundEntity> = refunds::table
+                .find(refund_id)
+                .filter(refunds::status.eq("PENDING"))
+                .for_update()
+                .get_result(tx)
+                .await
+                .optional()
+                .context("Failed to lock refund")?
+            else {
+                return refunds::table
+                    .find(refund_id)
+                    .get_result(tx)
+                    .await
+                    .context("Failed to get refund");
+            };
+
+            let provider_refund = match outcome {
+                Ok(provider_refund) => provider_refund,
+                Err(e) => {
+                    error!(
+                        refund_id = %refund_id,
+                        error = format!("{:#}", e),
+                        "Provider failed to refund"
+                    );
+
+                    // Kept as a record of the attempt, the payment itself is unchanged
+                    return diesel::update(refunds::table.find(refund_id))
+                        .set((
+                            refunds::status.eq("FAILED"),
+                            refunds::failure_reason.eq(format!("{:#}", e)),
+                        ))
+                        .returning(RefundEntity::as_returning())
+                        .get_result(tx)
+                        .await
+                        .context("Failed to update refund");
+                }
+            };
+
+            let refund: RefundEntity = diesel::update(refunds::table.find(refund.id))
+                .set((
+                    refunds::status.eq("SUCCEEDED"),
+                    refunds::provider_ref.eq(provider_refund.provider_ref),
+                ))
+                .returning(RefundEntity::as_returning())
+                .get_result(tx)
+                .await
+                .context("Failed to update refund")?;
+
+            let refunded: Vec<i64> = refunds::table
+                .filter(refunds::payment_id.eq(payment.id))
+                .filter(refunds::status.eq("SUCCEEDED"))
+                .select(refunds::amount_minor)
+                .load(tx)
+                .await
+                .context("Failed to get refunds")?;
+            let full = refunded.iter().sum::<i64>() >= to_minor(payment.amount);
+
+            diesel::update(payments::table.find(payment.id))
+                .set(payments::status.eq(if full {
+                    "REFUNDED"
+                } else {
+                    "PARTIALLY_REFUNDED"
+                }))
+                .execute(tx)
+                .await
+                .context("Failed to update payment")?;
+
+            // The order went on without this payment, there is nothing to tell it
+            if payment.captured_late_at.is_none() {
+                let returned_items = refund_items::table
+                    .filter(refund_items::refund_id.eq(refund.id))
+                    .order(refund_items::product_id.asc())
+                    .select((refund_items::product_id, refund_items::quantity))
+                    .load::<(i32, i32)>(tx)
+                    .await
+                    .context("Failed to get refund items")?
+                    .into_iter()
+                    .map(|(product_id, quantity)| OrderItem {
+                        product_id,
+                        quantity,
+                    })
+                    .collect();
+
+                let envelope = EventEnvelope::new(
+                    SERVICE_NAME,
+                    PaymentRefundedEvent {
+                        refund_id: refund.id,
+                        payment_id: payment.id,
+                        order_id: payment.order_id,
+                        amount_minor: refund.amount_minor,
+                        full,
+                        returned_items,
+                    },
+                )
+                .with_correlation_id(payment.correlation_id);
+
+                outbox::publish(tx, &envelope).await?;
+            }
+
+            metrics::counter!("payments_refunded_total").increment(1);
+            info!(
+                order_id = payment.order_id,
+                refund_id = %refund.id,
+                amount_minor = refund.amount_minor,
+                full,
+                "Payment has been refunded"
+            );
+
+            Ok(refund)
+        })
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(items: &[(i32, i32)]) -> BTreeMap<i32, i32> {
+        items.iter().copied().collect()
+    }
+
+    #[test]
+    fn to_minor_rounds_to_the_nearest_satang() {
+        // (baht, satang); most of these are not exact in f32
+        let amounts = [
+            (0.0, 0),
+            (0.01, 1),
+            (0.1, 10),
+            (0.29, 29),
+            (1.0, 100),
+            (19.99, 1999),
+            (100.1, 10010),
+            (1234.56, 123456),
+            (99999.99, 9999999),
+        ];
+
+        for (amount, minor) in amounts {
+            assert_eq!(to_minor(amount), minor, "{} baht", amount);
+        }
+    }
+
+    #[test]
+    fn refund_amount_defaults_to_what_is_left() {
+        assert_eq!(refund_amount(None, 1999).unwrap(), 1999);
+        assert_eq!(refund_amount(Some(500), 1999).unwrap(), 500);
+        assert_eq!(refund_amount(Some(1999), 1999).unwrap(), 1999);
+    }
+
+    #[test]
+    fn refund_amount_rejects_over_refunds() {
+        for (requested, refundable) in [
+            (Some(2000), 1999),
+            (Some(1), 0),
+            (None, 0),
+            (Some(0), 1999),
+            (Some(-100), 1999),
+        ] {
+            assert!(
+                matches!(
+                    refund_amount(requested, refundable),
+                    Err(AppError::InvalidRefundAmount { .. })
+                ),
+                "{:?} of {}",
+                requested,
+                refundable
+            );
+        }
+    }
+
+    #[test]
+    fn merge_adds_up_returned_products() {
+        let returned = [
+            OrderItem {
+                product_id: 2,
+                quantity: 1,
+            },
+            OrderItem {
+                product_id: 1,
+                quantity: 3,
+            },
+            OrderItem {
+                product_id: 2,
+                quantity: 2,
+            },
+        ];
+
+        assert_eq!(merge(&returned).unwrap(), items(&[(1, 3), (2, 3)]));
+    }
+
+    #[test]
+    fn merge_rejects_non_positive_quantities() {
+        for quantity in [0, -1] {
+            let returned = [OrderItem {
+                product_id: 1,
+                quantity,
+            }];
+
+            assert!(matches!(
+                merge(&returned),
+                Err(AppError::InvalidReturnedItems(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn check_returnable_allows_up_to_what_was_paid_for() {
+        let paid = items(&[(1, 3), (2, 1)]);
+
+        assert!(check_returnable(&items(&[(1, 3), (2, 1)]), &paid, &items(&[])).is_ok());
+        assert!(check_returnable(&items(&[(1, 1)]), &paid, &items(&[(1, 2)])).is_ok());
+    }
+
+    #[test]
+    fn check_returnable_rejects_more_than_was_purchased() {
+        let paid = items(&[(1, 3), (2, 1)]);
+
+        for (returned, already_returned) in [
+            (items(&[(1, 4)]), items(&[])),
+            (items(&[(2, 1)]), items(&[(2, 1)])),
+            (items(&[(1, 2)]), items(&[(1, 2)])),
+            // Never paid for
+            (items(&[(3, 1)]), items(&[])),
+        ] {
+            assert!(
+                matches!(
+                    check_returnable(&returned, &paid, &already_returned),
+                    Err(AppError::InvalidReturnedItems(_))
+                ),
+                "{:?} after {:?}",
+                returned,
+                already_returned
+            );
+        }
+    }
+}
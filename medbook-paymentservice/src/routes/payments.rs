@@ -2,28 +2,58 @@ use anyhow::{Context, Result};
 use axum::{
     Json, Router,
     extract::{Path, State},
+    middleware,
     response::IntoResponse,
     routing,
 };
-use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper};
 use diesel_async::{AsyncConnection, RunQueryDsl};
-use medbook_events::OrderPaymentSuccessEvent;
-use serde::Deserialize;
-use tracing::info;
+use medbook_common::{
+    admin::{AdminToken, admin_authorization},
+    app_error::BaseError,
+    stage::Stage,
+};
+use medbook_events::OrderItem;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
 use uuid::Uuid;
 
 use crate::{
     app_error::AppError,
     app_state::AppState,
-    models::{CreateOutboxEntity, OutboxEntity, PaymentEntity},
-    schema::{outbox, payments},
+    models::{PaymentEntity, RefundEntity},
+    payment_failure, payment_success, promptpay,
+    refunds::{self, RefundRequest},
+    routes::webhooks,
+    schema::{payments, refunds as refunds_table},
 };
 
-pub fn routes() -> Router<AppState> {
-    Router::new()
+pub fn routes() -> anyhow::Result<Router<AppState>> {
+    // Refunds are given by the pharmacy, not the patients
+    let admin = Router::new()
+        .route("/{id}/refund", routing::post(refund_payment))
+        .route("/{id}/refunds", routing::get(get_refunds))
+        .route_layer(middleware::from_fn_with_state(
+            AdminToken::from_env()?,
+            admin_authorization,
+        ));
+
+    let router = Router::new()
         .route("/{id}", routing::get(get_payment_from_id))
         .route("/", routing::get(get_payments))
-        .route("/{id}/mock-pay", routing::post(mock_pay_for_id))
+        .route("/providers", routing::get(get_providers))
+        .route("/{id}/qr", routing::get(get_qr_for_id))
+        .merge(admin)
+        .nest("/webhooks", webhooks::routes());
+
+    // Unauthenticated, so only for trying the flow out without a provider
+    Ok(if Stage::from_env()?.allows_test_tools() {
+        router
+            .route("/{id}/mock-pay", routing::post(mock_pay_for_id))
+            .route("/{id}/mock-fail", routing::post(mock_fail_for_id))
+    } else {
+        router
+    })
 }
 
 #[derive(Deserialize, Debug)]
@@ -67,6 +97,54 @@ async fn get_payments(State(state): State<AppState>) -> Result<impl IntoResponse
     Ok(Json(payments))
 }
 
+/// The providers payments can be made with.
+async fn get_providers(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.providers.list())
+}
+
+#[derive(Serialize, Debug)]
+pub struct QrRes {
+    /// The EMVCo payload encoded in the QR code.
+    pub payload: String,
+    pub svg: String,
+}
+
+/// The QR code to scan to pay a pending payment, for providers paid that way.
+#[instrument(skip_all, fields(payment_id = %id))]
+async fn get_qr_for_id(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let conn = &mut state
+        .db_pool
+        .get()
+        .await
+        .context("Failed to obtain a DB connection pool")?;
+
+    let payment: PaymentEntity = payments::table
+        .find(id)
+        .get_result(conn)
+        .await
+        .optional()
+        .context("Failed to get payment")?
+        .ok_or_else(|| BaseError::NotFound(format!("payments(id={})", id)))?;
+
+    if payment.status != "PENDING" {
+        return Err(AppError::NotPayable(id, payment.status));
+    }
+
+    let payload = state
+        .providers
+        .for_payment(&payment)?
+        .qr_payload(&payment)?
+        .ok_or_else(|| BaseError::NotFound(format!("payments(id={})/qr", id)))?;
+    let svg = promptpay::svg(&payload)?;
+
+    Ok(Json(QrRes { payload, svg }))
+}
+
+/// Simulates the provider confirming the payment.
+#[instrument(skip_all, fields(payment_id = %id))]
 async fn mock_pay_for_id(
     Path(id): Path<Uuid>,
     State(state): State<AppState>,
@@ -78,50 +156,124 @@ async fn mock_pay_for_id(
         .context("Failed to obtain a DB connection pool")?;
 
     let updated_payment = conn
+        .transaction(|tx| Box::pin(async move { payment_success::succeed(tx, id).await }))
+        .await
+        .context("Transaction failed")?
+        .ok_or_else(|| BaseError::NotFound(format!("payments(id={}, status=PENDING)", id)))?;
+
+    info!(
+        order_id = updated_payment.order_id,
+        "Updated payment's status to SUCCESS"
+    );
+
+    Ok(Json(updated_payment))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MockFailReq {
+    pub reason: Option<String>,
+}
+
+/// Simulates the provider declining the payment.
+#[instrument(skip_all, fields(payment_id = %id))]
+async fn mock_fail_for_id(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    body: Option<Json<MockFailReq>>,
+) -> Result<impl IntoResponse, AppError> {
+    let conn = &mut state
+        .db_pool
+        .get()
+        .await
+        .context("Failed to obtain a DB connection pool")?;
+
+    let reason = body
+        .and_then(|Json(body)| body.reason)
+        .unwrap_or_else(|| "Declined by the provider".into());
+
+    let failed_payment = conn
+        .transaction(|tx| Box::pin(async move { payment_failure::fail(tx, id, &reason).await }))
+        .await
+        .context("Transaction failed")?
+        .ok_or_else(|| BaseError::NotFound(format!("payments(id={}, status=PENDING)", id)))?;
+
+    info!(
+        order_id = failed_payment.order_id,
+        "Updated payment's status to FAILED"
+    );
+
+    Ok(Json(failed_payment))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RefundReq {
+    /// In satang, defaults to everything that has not been refunded yet.
+    pub amount_minor: Option<i64>,
+    pub reason: Option<String>,
+    /// Items the patient handed back, to be restocked.
+    #[serde(default)]
+    pub returned_items: Vec<OrderItem>,
+}
+
+/// Refunds a paid payment, fully or in part. Partial refunds can be repeated
+/// until the whole amount has been given back.
+#[instrument(skip_all, fields(payment_id = %id))]
+async fn refund_payment(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Json(body): Json<RefundReq>,
+) -> Result<impl IntoResponse, AppError> {
+    let conn = &mut state
+        .db_pool
+        .get()
+        .await
+        .context("Failed to obtain a DB connection pool")?;
+
+    // Committed before the provider is asked, so a crash in between leaves a
+    // PENDING refund for the retry job rather than money given back unrecorded
+    let refund = conn
         .transaction(|tx| {
             Box::pin(async move {
-                // 1. Update status to SUCCESS
-                let updated_payment: PaymentEntity = diesel::update(
-                    payments::table
-                        .filter(payments::id.eq(id))
-                        .filter(payments::status.eq("PENDING")),
+                refunds::begin(
+                    tx,
+                    id,
+                    RefundRequest {
+                        amount_minor: body.amount_minor,
+                        reason: body.reason,
+                        returned_items: body.returned_items,
+                    },
                 )
-                .set(payments::status.eq("SUCCESS"))
-                .returning(PaymentEntity::as_returning())
-                .get_result(tx)
                 .await
-                .context("Failed to update payment")?;
-
-                info!(
-                    "Updated payment #{}'s status to SUCCESS",
-                    updated_payment.id
-                );
-
-                // 2. Create outbox
-                let updated_payment_clone = updated_payment.clone();
-                let outbox = diesel::insert_into(outbox::table)
-                    .values(CreateOutboxEntity {
-                        event_type: "orders.payment_success".into(),
-                        payload: serde_json::to_string(&OrderPaymentSuccessEvent {
-                            payment_id: updated_payment_clone.id,
-                            order_id: updated_payment_clone.order_id,
-                            amount: updated_payment_clone.amount,
-                            provider: updated_payment_clone.provider,
-                        })
-                        .context("Failed to serialize OrderPaymentSuccessEvent")?,
-                    })
-                    .returning(OutboxEntity::as_returning())
-                    .get_result(tx)
-                    .await
-                    .context("Outbox creation failed")?;
-
-                info!("Outbox created: {:?}", outbox);
-
-                Ok::<PaymentEntity, anyhow::Error>(updated_payment)
             })
         })
+        .await?;
+
+    let refund = refunds::settle(conn, &state.providers, refund.id).await?;
+
+    if refund.status == "FAILED" {
+        return Err(AppError::RefundFailed(refund.id));
+    }
+
+    Ok(Json(refund))
+}
+
+async fn get_refunds(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let conn = &mut state
+        .db_pool
+        .get()
         .await
-        .context("Transaction failed")?;
+        .context("Failed to obtain a DB connection pool")?;
 
-    Ok(Json(updated_payment))
+    let refunds: Vec<RefundEntity> = refunds_table::table
+        .filter(refunds_table::payment_id.eq(id))
+        .order(refunds_table::created_at.asc())
+        .select(RefundEntity::as_select())
+        .get_results(conn)
+        .await
+        .context("Failed to get refunds")?;
+
+    Ok(Json(refunds))
 }
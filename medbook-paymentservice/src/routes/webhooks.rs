@@ -0,0 +1,174 @@
+use anyhow::Context;
+use axum::{
+    Router,
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing,
+};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use medbook_common::app_error::BaseError;
+use tracing::{Span, error, field, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    app_error::AppError,
+    app_state::AppState,
+    models::{CreateWebhookEventEntity, PaymentEntity},
+    payment_failure, payment_success,
+    providers::ChargeStatus,
+    refunds::{self, RefundRequest},
+    schema::{payments, webhook_events},
+};
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/{provider}", routing::post(receive_webhook))
+}
+
+/// Settles a payment from a signed provider notification. Redeliveries of a
+/// webhook already handled are acknowledged without effect, and a payment the
+/// provider took after it was voided or failed is refunded.
+#[instrument(skip_all, fields(provider = provider_name, payment_id = field::Empty))]
+async fn receive_webhook(
+    Path(provider_name): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    let provider = state
+        .providers
+        .get(&provider_name)
+        .ok_or_else(|| BaseError::NotFound(format!("payment_providers(name={})", provider_name)))?;
+
+    let event = provider.verify_webhook(&headers, &body).map_err(|e| {
+        warn!(error = format!("{:#}", e), "Rejected webhook");
+        AppError::InvalidWebhook
+    })?;
+
+    let conn = &mut state
+        .db_pool
+        .get()
+        .await
+        .context("Failed to obtain a DB connection pool")?;
+
+    let late_refund = conn
+        .transaction(|tx| {
+            Box::pin(async move {
+                let payment: PaymentEntity = payments::table
+                    .filter(payments::provider.eq(&provider_name))
+                    .filter(payments::provider_ref.eq(&event.provider_ref))
+                    .for_update()
+                    .get_result(tx)
+                    .await
+                    .optional()
+                    .context("Failed to get payment")?
+                    .ok_or_else(|| {
+                        BaseError::NotFound(format!(
+                            "payments(provider_ref={})",
+                            event.provider_ref
+                        ))
+                    })?;
+                Span::current().record("payment_id", field::display(payment.id));
+
+                let inserted = diesel::insert_into(webhook_events::table)
+                    .values(CreateWebhookEventEntity {
+                        provider: provider_name.clone(),
+                        event_id: event.id.clone(),
+                        payment_id: Some(payment.id),
+                    })
+                    .on_conflict_do_nothing()
+                    .execute(tx)
+                    .await
+                    .context("Failed to record webhook")?;
+
+                if inserted == 0 {
+                    info!(event_id = event.id, "Webhook was already handled");
+                    return Ok(None);
+                }
+
+                // The money was taken although the order no longer waits for it
+                if event.status == ChargeStatus::Succeeded
+                    && matches!(payment.status.as_str(), "VOIDED" | "FAILED")
+                    && payment.captured_late_at.is_none()
+                {
+                    return capture_late(tx, &payment).await.map(Some);
+                }
+
+                // Both only touch PENDING payments, so late or out of order webhooks are no-ops
+                let settled = match &event.status {
+                    ChargeStatus::Pending => None,
+                    ChargeStatus::Succeeded => payment_success::succeed(tx, payment.id).await?,
+                    ChargeStatus::Failed { reason } => {
+                        payment_failure::fail(tx, payment.id, reason).await?
+                    }
+                };
+
+                match settled {
+                    Some(payment) => info!(
+                        order_id = payment.order_id,
+                        status = payment.status,
+                        "Payment has been settled by its provider"
+                    ),
+                    None => info!(
+                        event_id = event.id,
+                        status = payment.status,
+                        "Webhook did not change the payment"
+                    ),
+                }
+
+                Ok::<_, AppError>(None)
+            })
+        })
+        .await?;
+
+    // After the commit, so the provider is not waited on inside the transaction
+    if let Some(refund_id) = late_refund
+        && let Err(e) = refunds::settle(conn, &state.providers, refund_id).await
+    {
+        warn!(
+            refund_id = %refund_id,
+            error = format!("{:#}", e),
+            "Failed to settle refund, leaving it to the retry job"
+        );
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Records that `payment` was captured after it was voided or failed and starts
+/// refunding it in full. Returns the id of the PENDING refund.
+async fn capture_late(
+    conn: &mut AsyncPgConnection,
+    payment: &PaymentEntity,
+) -> Result<Uuid, AppError> {
+    diesel::update(payments::table.find(payment.id))
+        .set(payments::captured_late_at.eq(diesel::dsl::now))
+        .execute(conn)
+        .await
+        .context("Failed to update payment")?;
+
+    let refund = refunds::begin(
+        conn,
+        payment.id,
+        RefundRequest {
+            reason: Some(format!(
+                "Captured after the payment was {}",
+                payment.status.to_lowercase()
+            )),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    metrics::counter!("payments_captured_after_close_total").increment(1);
+    error!(
+        order_id = payment.order_id,
+        status = payment.status,
+        refund_id = %refund.id,
+        "Payment was captured after it was closed, refunding it"
+    );
+
+    Ok(refund.id)
+}
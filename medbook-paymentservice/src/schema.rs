@@ -1,14 +1,5 @@
 // @generated automatically by Diesel CLI.
 
-diesel::table! {
-    outbox (id) {
-        id -> Int4,
-        event_type -> Text,
-        payload -> Text,
-        status -> Text,
-    }
-}
-
 diesel::table! {
     payments (id) {
         id -> Uuid,
@@ -23,7 +14,63 @@ diesel::table! {
         failure_reason -> Nullable<Text>,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        correlation_id -> Uuid,
+        captured_late_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    payment_items (payment_id, product_id) {
+        payment_id -> Uuid,
+        product_id -> Int4,
+        quantity -> Int4,
+    }
+}
+
+diesel::table! {
+    refund_items (refund_id, product_id) {
+        refund_id -> Uuid,
+        product_id -> Int4,
+        quantity -> Int4,
+    }
+}
+
+diesel::table! {
+    refunds (id) {
+        id -> Uuid,
+        payment_id -> Uuid,
+        reason -> Nullable<Text>,
+        #[max_length = 32]
+        status -> Varchar,
+        #[max_length = 128]
+        provider_ref -> Nullable<Varchar>,
+        failure_reason -> Nullable<Text>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        amount_minor -> Int8,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(outbox, payments,);
+diesel::table! {
+    webhook_events (provider, event_id) {
+        #[max_length = 64]
+        provider -> Varchar,
+        #[max_length = 128]
+        event_id -> Varchar,
+        payment_id -> Nullable<Uuid>,
+        received_at -> Timestamptz,
+    }
+}
+
+diesel::joinable!(payment_items -> payments (payment_id));
+diesel::joinable!(refund_items -> refunds (refund_id));
+diesel::joinable!(refunds -> payments (payment_id));
+diesel::joinable!(webhook_events -> payments (payment_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    payment_items,
+    payments,
+    refund_items,
+    refunds,
+    webhook_events,
+);